@@ -0,0 +1,52 @@
+//! Compare the inline ValueMap used by patches against a plain HashMap
+//!
+//! Each iteration builds a map with the given number of keys and then looks every one of them up, which
+//! is roughly what a diff followed by an apply does.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::collections::HashMap;
+
+use protean::{PatchOp, ValueMap};
+
+fn entries(count: usize) -> Vec<(String, PatchOp)> {
+  (0..count)
+    .map(|i| (format!("field_{}", i), PatchOp::Set(serde_json::json!(i))))
+    .collect()
+}
+
+fn build_and_read(c: &mut Criterion) {
+  let mut group = c.benchmark_group("build_and_read");
+  for count in [1, 2, 8, 64].iter() {
+    let entries = entries(*count);
+
+    group.bench_with_input(
+      BenchmarkId::new("HashMap", count),
+      &entries,
+      |b, entries| {
+        b.iter(|| {
+          let map: HashMap<String, PatchOp> = entries.iter().cloned().collect();
+          for (key, _) in entries.iter() {
+            black_box(map.get(key));
+          }
+        })
+      },
+    );
+
+    group.bench_with_input(
+      BenchmarkId::new("ValueMap", count),
+      &entries,
+      |b, entries| {
+        b.iter(|| {
+          let map: ValueMap = entries.iter().cloned().collect();
+          for (key, _) in entries.iter() {
+            black_box(map.get(key));
+          }
+        })
+      },
+    );
+  }
+  group.finish();
+}
+
+criterion_group!(benches, build_and_read);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+//! Feed arbitrary bytes to Patch::from_json, which must return an error rather than panic
+//!
+//! Run with `cargo fuzz run from_json` from the protean directory
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+  if let Ok(json) = std::str::from_utf8(data) {
+    if let Ok(patch) = protean::Patch::from_json(json) {
+      // Anything accepted has to survive the trip back out
+      let round_trip = protean::Patch::from_json(&patch.to_json().unwrap()).unwrap();
+      assert_eq!(round_trip, patch);
+    }
+  }
+});
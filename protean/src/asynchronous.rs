@@ -0,0 +1,84 @@
+//! Applying patches with validation that has to wait on something outside of the process
+//!
+//! Validator is synchronous, which rules out checks such as making sure a foreign key exists in a
+//! database. An AsyncValidator returns a future instead and is only run by AsyncPatchwork::apply_async,
+//! so the synchronous path works exactly as it does without the `async` feature.
+//!
+//! Patches hold their validators in an Rc, so none of the futures here are Send.
+
+use anyhow::Result;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::{Patch, PatchOp, Patchwork};
+
+/// A boxed future borrowing from the validator and the values being checked
+pub type BoxFuture<'f, T> = Pin<Box<dyn Future<Output = T> + 'f>>;
+
+/// Checks a value can be stored under a key, possibly waiting on something else to find out
+pub trait AsyncValidator {
+  /// Resolve to an error if the value is not allowed to be stored under the key
+  fn validate<'f>(
+    &'f self,
+    key: &'f str,
+    value: &'f serde_json::Value,
+  ) -> BoxFuture<'f, Result<()>>;
+
+  /// Check a single change
+  ///
+  /// Like Validator::validate_op, removals are allowed by default.
+  fn validate_op<'f>(&'f self, key: &'f str, op: &'f PatchOp) -> BoxFuture<'f, Result<()>> {
+    match op.value() {
+      Some(value) => self.validate(key, value),
+      None => Box::pin(async { Ok(()) }),
+    }
+  }
+}
+
+impl Patch {
+  /// Add a validator that apply_async waits on before anything is changed
+  pub fn with_async_validator(&self, validator: Rc<dyn AsyncValidator>) -> Patch {
+    Patch {
+      async_validator: Some(validator),
+      ..self.clone()
+    }
+  }
+
+  /// Run both validators over every change in the patch
+  ///
+  /// The synchronous one goes first so cheap checks can fail a patch before any awaiting is done. The
+  /// changes are checked one at a time.
+  pub async fn validate_async(&self) -> Result<()> {
+    self.validate()?;
+    if let Some(validator) = &self.async_validator {
+      for (key, op) in self.iter() {
+        validator.validate_op(key, op).await?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Patchwork with an apply that can wait on an AsyncValidator
+///
+/// This is implemented for every Patchwork type. The method is apply_async rather than apply so having
+/// both traits in scope doesn't make calls to Patchwork::apply ambiguous.
+pub trait AsyncPatchwork<'a>: Patchwork<'a> {
+  /// Validate the patch, including its AsyncValidator, and then apply all of it or none of it
+  ///
+  /// Nothing is touched until validation has finished, so the struct is never seen half patched while
+  /// waiting.
+  fn apply_async<'f>(&'f mut self, patch: &'f Patch) -> BoxFuture<'f, Result<()>>
+  where
+    Self: 'f,
+  {
+    Box::pin(async move {
+      patch.validate_async().await?;
+      self.check_patch(patch)?;
+      patch.try_apply_all(self)
+    })
+  }
+}
+
+impl<'a, T> AsyncPatchwork<'a> for T where T: Patchwork<'a> {}
@@ -13,4 +13,43 @@ pub enum ProteanError {
 
   #[error("The patch did not set a key")]
   NoKeySet,
+
+  #[error("No handler has been registered for patch type '{0}'")]
+  UnregisteredPatchType(String),
+
+  #[error("The patch key '{0}' does not match any value in the struct")]
+  UnknownKey(String),
+
+  #[error("There is no value at '{0}' for the patch to change")]
+  MissingValue(String),
+
+  #[error("Could not deserialize the value for key '{0}': {1}")]
+  DeserializeFailed(String, String),
+
+  #[error("The patch is malformed: {0}")]
+  MalformedPatch(String),
+
+  #[error("The value at '{0}' cannot be removed")]
+  CannotRemove(String),
+
+  #[error("A patch cannot be applied through a shared reference")]
+  ImmutableReference,
+
+  #[error("The key '{0}' was found more than once")]
+  DuplicateKey(String),
+
+  #[error("The patch conflicts with changes made to its base at {0:?}")]
+  RebaseConflict(Vec<String>),
+
+  #[error("The history index {0} is out of range, which ends at {1}")]
+  HistoryOutOfRange(usize, usize),
+
+  #[error("There is no history left to pop")]
+  EmptyHistory,
+
+  #[error("There is no checkpoint named '{0}'")]
+  UnknownCheckpoint(String),
+
+  #[error("The lock around '{0}' was poisoned by a panic while it was held")]
+  PoisonedLock(String),
 }
@@ -0,0 +1,147 @@
+//! The record of patches applied to a Historic struct
+
+use anyhow::{Context, Result};
+
+use crate::{Patch, ProteanError};
+
+/// A patch that was applied along with the patch that reverses it
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+  /// The patch as it was applied
+  pub forward: Patch,
+
+  /// Returns the struct to the state it was in before forward was applied
+  pub undo: Patch,
+}
+
+/// An ordered list of the patches applied to a struct, oldest first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct History {
+  entries: Vec<HistoryEntry>,
+
+  /// Named savepoints and the number of entries there were when each was taken, oldest first
+  checkpoints: Vec<(String, usize)>,
+
+  /// The newest version handed out, which never goes back down when entries are removed or squashed
+  version: u64,
+}
+
+impl History {
+  pub fn new() -> History {
+    History::default()
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  pub fn entries(&self) -> &[HistoryEntry] {
+    &self.entries
+  }
+
+  /// The version of the newest patch recorded, or 0 if nothing has been
+  pub fn version(&self) -> u64 {
+    self.version
+  }
+
+  /// Reserve the version for the next patch to be recorded
+  ///
+  /// Versions keep counting up when entries are popped, truncated or squashed, so a version is never
+  /// given to two different patches.
+  pub fn next_version(&mut self) -> u64 {
+    self.version += 1;
+    self.version
+  }
+
+  /// Add a patch that has just been applied
+  pub fn push(&mut self, forward: Patch, undo: Patch) {
+    self.entries.push(HistoryEntry { forward, undo });
+  }
+
+  /// Remove the newest entry without touching the struct
+  ///
+  /// Any checkpoint taken after the entry was added is dropped along with it.
+  pub fn pop(&mut self) -> Option<HistoryEntry> {
+    let entry = self.entries.pop()?;
+    self.drop_checkpoints_after(self.entries.len());
+    Some(entry)
+  }
+
+  /// Remove every entry after the first len, without touching the struct
+  pub fn truncate(&mut self, len: usize) {
+    self.entries.truncate(len);
+    self.drop_checkpoints_after(len);
+  }
+
+  /// Name the current point in the history so it can be returned to later
+  ///
+  /// Reusing a name moves the checkpoint, the same as an SQL savepoint.
+  pub fn checkpoint(&mut self, name: &str) {
+    self.checkpoints.retain(|(existing, _)| existing != name);
+    self
+      .checkpoints
+      .push((name.to_string(), self.entries.len()));
+  }
+
+  /// The number of entries there were when the checkpoint was taken
+  pub fn checkpoint_index(&self, name: &str) -> Result<usize> {
+    match self
+      .checkpoints
+      .iter()
+      .find(|(existing, _)| existing == name)
+    {
+      Some((_, index)) => Ok(*index),
+      None => Err(ProteanError::UnknownCheckpoint(name.to_string()))
+        .context("The checkpoint was never taken or has already been rolled back past"),
+    }
+  }
+
+  /// Forget the checkpoints for states that are no longer in the history
+  fn drop_checkpoints_after(&mut self, len: usize) {
+    self.checkpoints.retain(|(_, index)| *index <= len);
+  }
+
+  /// Merge the entries in from..to into a single one
+  ///
+  /// The forward patches are composed oldest first, and the undo patches newest first since that is the
+  /// order they have to be applied in.
+  pub fn squash(&mut self, from: usize, to: usize) -> Result<()> {
+    if to > self.entries.len() || from > to {
+      return Err(ProteanError::HistoryOutOfRange(to, self.entries.len()))
+        .context(format!("Cannot squash {}..{}", from, to));
+    }
+    if to - from < 2 {
+      return Ok(());
+    }
+
+    let squashed = &self.entries[from..to];
+    let mut forward = squashed[0].forward.clone();
+    for entry in squashed[1..].iter() {
+      forward = forward.compose(&entry.forward)?;
+    }
+    let mut undo = squashed[squashed.len() - 1].undo.clone();
+    for entry in squashed[..squashed.len() - 1].iter().rev() {
+      undo = undo.compose(&entry.undo)?;
+    }
+
+    self
+      .entries
+      .splice(from..to, std::iter::once(HistoryEntry { forward, undo }));
+
+    // States inside of the range no longer exist, and those after it have moved down
+    let removed = to - from - 1;
+    self
+      .checkpoints
+      .retain(|(_, index)| *index <= from || *index >= to);
+    for (_, index) in self.checkpoints.iter_mut() {
+      if *index >= to {
+        *index -= removed;
+      }
+    }
+    Ok(())
+  }
+}
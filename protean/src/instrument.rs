@@ -0,0 +1,82 @@
+//! Tracing for diff and apply
+//!
+//! With the `tracing` feature each call gets a span recording the patch type, the number of keys and
+//! whether it succeeded, so nested structs show up as a tree of spans. Without it the same details are
+//! written with log::debug.
+
+use anyhow::Result;
+
+use crate::Patch;
+
+/// Run an apply inside of a span describing the patch
+pub fn trace_apply<F>(patch: &Patch, apply: F) -> Result<()>
+where
+  F: FnOnce() -> Result<()>,
+{
+  #[cfg(feature = "tracing")]
+  {
+    let span = tracing::debug_span!(
+      "apply",
+      patch_type = patch.patch_type(),
+      keys = patch.len(),
+      ok = tracing::field::Empty
+    );
+    let _entered = span.enter();
+    let result = apply();
+    span.record("ok", result.is_ok());
+    result
+  }
+
+  #[cfg(not(feature = "tracing"))]
+  {
+    let result = apply();
+    log::debug!(
+      "apply {} ({} keys): {}",
+      patch.patch_type(),
+      patch.len(),
+      outcome(result.is_ok())
+    );
+    result
+  }
+}
+
+/// Run a diff inside of a span, recording how many keys it found
+pub fn trace_diff<F>(patch_type: &str, diff: F) -> Result<Patch>
+where
+  F: FnOnce() -> Result<Patch>,
+{
+  #[cfg(feature = "tracing")]
+  {
+    let span = tracing::debug_span!(
+      "diff",
+      patch_type,
+      keys = tracing::field::Empty,
+      ok = tracing::field::Empty
+    );
+    let _entered = span.enter();
+    let result = diff();
+    if let Ok(patch) = &result {
+      span.record("keys", patch.len());
+    }
+    span.record("ok", result.is_ok());
+    result
+  }
+
+  #[cfg(not(feature = "tracing"))]
+  {
+    let result = diff();
+    match &result {
+      Ok(patch) => log::debug!("diff {} ({} keys): ok", patch_type, patch.len()),
+      Err(_) => log::debug!("diff {}: {}", patch_type, outcome(false)),
+    }
+    result
+  }
+}
+
+#[cfg(not(feature = "tracing"))]
+fn outcome(ok: bool) -> &'static str {
+  match ok {
+    true => "ok",
+    false => "failed",
+  }
+}
@@ -7,13 +7,60 @@
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::Debug;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 pub mod error;
 pub use error::ProteanError;
 
+mod instrument;
+
+pub mod history;
+pub use history::{History, HistoryEntry};
+
+pub mod registry;
+pub use registry::PatchRegistry;
+
+pub mod value_map;
+pub use value_map::ValueMap;
+
+#[cfg(feature = "async")]
+pub mod asynchronous;
+#[cfg(feature = "async")]
+pub use asynchronous::{AsyncPatchwork, AsyncValidator, BoxFuture};
+
+#[cfg(feature = "protean_derive")]
+pub use protean_derive::Patchwork;
+
+// Paths used by the code generated in protean_derive, so deriving doesn't require extra dependencies
+#[doc(hidden)]
+pub mod __private {
+  pub use crate::instrument::{trace_apply, trace_diff};
+  pub use anyhow::Result;
+  pub use serde::{Deserialize, Serialize};
+  pub use serde_json::Value;
+
+  /// Check the keys of a struct with flattened fields don't collide
+  ///
+  /// This runs on every diff and apply rather than only when both sides change, so a collision is found
+  /// as soon as the struct is used instead of the first time it happens to overwrite something.
+  pub fn unique_keys(keys: Vec<String>) -> Result<()> {
+    let mut seen = std::collections::HashSet::new();
+    for key in keys {
+      if !seen.insert(key.clone()) {
+        return Err(
+          anyhow::Error::new(crate::ProteanError::DuplicateKey(key))
+            .context("A flattened field shares a key with another field"),
+        );
+      }
+    }
+    Ok(())
+  }
+}
+
 // macro_rules! create_patch {
 //   // Doing the patch macro here
 // }
@@ -43,10 +90,98 @@ macro_rules! patch {
 /// The two use cases I'm creating this for:
 /// - Sending out events based on changes to cached values
 /// - Rollback on error based on original values
+///
+/// The struct owns its History, which should be skipped by both the patch and serde so recording a
+/// change doesn't change the history's own diff:
+/// ```ignore
+/// #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+/// struct User {
+///   name: String,
+///   #[patch(skip)]
+///   #[serde(skip)]
+///   history: History,
+/// }
+/// ```
 pub trait Historic<'a, SubClass = Self>: Patchwork<'a> {
-  // HACK: Fix language for pop
-  // Revert to the previous state and return a patch that can undo the revert
-  // fn pop(&mut self) -> Result<Patch>
+  fn history(&self) -> &History;
+  fn history_mut(&mut self) -> &mut History;
+
+  /// Apply the patch and add it to the history
+  ///
+  /// The recorded patch is stamped with the next version from the history, counting from one, so
+  /// changes_since can find it later.
+  fn record(&mut self, patch: &Patch) -> Result<()> {
+    let before = self.snapshot()?;
+    patch.try_apply_all(self)?;
+    let undo = self.diff(&before)?;
+    let mut forward = patch.clone();
+    forward.stamp(self.history_mut().next_version());
+    self.history_mut().push(forward, undo);
+    Ok(())
+  }
+
+  /// A single patch with every change recorded after the given version
+  ///
+  /// This works on keys rather than entries, so it still finds the right changes after a squash.
+  fn changes_since(&self, version: u64) -> Result<Patch> {
+    let mut changes = self.new_patch();
+    for entry in self.history().entries() {
+      changes = changes.compose(&entry.forward)?;
+    }
+    Ok(changes.retain_changed_since(version))
+  }
+
+  /// Rebuild the struct as it was after the first `index` patches were applied
+  ///
+  /// at(0) is the struct before anything was recorded and at(history.len()) is the current state
+  fn at(&self, index: usize) -> Result<Self> {
+    let len = self.history().len();
+    if index > len {
+      return Err(ProteanError::HistoryOutOfRange(index, len))
+        .context("Cannot rebuild a state that hasn't happened yet");
+    }
+    let mut value = self.snapshot()?;
+    for entry in self.history().entries()[index..].iter().rev() {
+      value.apply(&entry.undo)?;
+    }
+    Ok(value)
+  }
+
+  /// Revert to the previous state and return a patch that can undo the revert
+  fn pop(&mut self) -> Result<Patch> {
+    let entry = match self.history().entries().last() {
+      Some(entry) => entry.clone(),
+      None => return Err(ProteanError::EmptyHistory).context("Tried to pop an empty history"),
+    };
+    entry.undo.try_apply_all(self)?;
+    self.history_mut().pop();
+    Ok(entry.forward)
+  }
+
+  /// Replace the patches in from..to with a single one that has the same effect
+  ///
+  /// States outside of the range are untouched, but everything after it moves down by the number of
+  /// patches that were removed. An empty range does nothing. Checkpoints inside of the range are dropped.
+  fn squash(&mut self, from: usize, to: usize) -> Result<()> {
+    self.history_mut().squash(from, to)
+  }
+
+  /// Name the current state so rollback_to can return to it
+  fn checkpoint(&mut self, name: &str) {
+    self.history_mut().checkpoint(name)
+  }
+
+  /// Undo every patch recorded since the checkpoint, newest first
+  ///
+  /// The state is rebuilt before anything is changed, so a failing undo leaves the struct as it was. The
+  /// checkpoint itself is kept so it can be rolled back to again, but any taken after it are dropped.
+  fn rollback_to(&mut self, name: &str) -> Result<()> {
+    let index = self.history().checkpoint_index(name)?;
+    let mut state = self.at(index)?;
+    self.restore(&mut state);
+    self.history_mut().truncate(index);
+    Ok(())
+  }
 }
 
 /// A method of creating and detecting mutations between structs
@@ -54,39 +189,195 @@ pub trait Historic<'a, SubClass = Self>: Patchwork<'a> {
 /// This is a deeper comparator than the standard Eq/PartialEq, returning a patch listing the differences
 /// between two instances of the same type. This is designed to work in the same way unix diff works, and the
 /// result is a Patch where
-pub trait Patchwork<'a, SubClass = Self>: Debug + Clone + Serialize + Deserialize<'a> {
+///
+/// Deserialize isn't required, so references can diff through to the value they point at. Applying a
+/// patch usually needs it though, so the container impls ask for it on their contents.
+#[diagnostic::on_unimplemented(
+  message = "`{Self}` does not implement Patchwork",
+  label = "this can't be diffed or patched",
+  note = "derive Patchwork for the type, or mark the field with #[patch(skip)]"
+)]
+pub trait Patchwork<'a, SubClass = Self>: Debug + Clone + Serialize {
+  /// Create an empty patch for this type
+  ///
+  /// The default uses the full path of the type from std::any::type_name, which can change between
+  /// compiler versions. The derive overrides it with just the struct name so it is stable across crates.
   fn new_patch(&self) -> Patch {
     // The validator is going to be generated by the macro. If manually implemented, it leaves items open for
     // panic and will be very difficult to debug
-    let validator = |_key, _value| {
-      // log::debug!("In the Patchwork Validator for 'STRUCT NAME HERE'");
-      // log::debug!("key='{:#?}', value='{:#?}'", key, value);
-
+    let validator = FnValidator::new(|_key, _value| {
       // TODO: Validate key path
       // TODO: Validate value is correct
 
       Ok(())
-    };
+    });
 
-    Patch {
-      // THINK: Unique Uuid hashed from type name and full version?
-      patch_type: "STRUCT NAME HERE".to_string(),
-      key: None,
-      validator: Rc::new(validator),
-      value_map: HashMap::new(),
-    }
+    // THINK: Unique Uuid hashed from type name and full version?
+    Patch::new(std::any::type_name::<Self>()).with_validator(Rc::new(validator))
+  }
+
+  /// The top level keys a patch for this type can contain, if they are known ahead of time
+  ///
+  /// The derive uses this to work out which keys belong to a flattened field, so only flatten types that
+  /// report their keys. The default doesn't know any.
+  fn field_keys(&self) -> Vec<String> {
+    Vec::new()
   }
 
+  /// Mutate the struct using the values stored in the patch
+  ///
+  /// The patch is borrowed rather than consumed, so the same patch can be applied to several instances.
+  /// Keys are applied one at a time, so use Patch::try_apply_all when a failure must not leave the struct
+  /// partially patched.
   fn apply(&mut self, patch: &Patch) -> Result<()> {
-    log::debug!("Applying patch:\n{}", patch);
-    // for key in patch.value_map.
-    // Split key (recursive calls)
+    instrument::trace_apply(patch, || {
+      // for key in patch.value_map.
+      // Split key (recursive calls)
 
-    Ok(())
+      Ok(())
+    })
+  }
+
+  /// Apply a patch and list the keys that ended up with a different value
+  ///
+  /// A patch can set a value to what it already was, so this only reports real mutations. The default
+  /// compares the serialized struct before and after, while the derive asks each field instead so
+  /// renamed keys match the ones used in the patch. The result is sorted.
+  fn apply_changes(&mut self, patch: &Patch) -> Result<Vec<String>> {
+    let before = serde_json::to_value(&*self)?;
+    self.apply(patch)?;
+    let mut changed = Vec::new();
+    changed_paths(
+      "&self",
+      &before,
+      &serde_json::to_value(&*self)?,
+      &mut changed,
+    );
+    changed.sort();
+    Ok(changed)
+  }
+
+  /// Apply the keys the type understands and return the ones it skipped
+  ///
+  /// This is for patches made by a newer version of the type, so fields it doesn't know about are left
+  /// out along with everything nested under them. Each field is checked with all of its keys at once,
+  /// since an Add can depend on the one before it. Any other problem is still an error, and nothing is
+  /// applied when one is found.
+  fn apply_partial(&mut self, patch: &Patch) -> Result<Vec<String>> {
+    let fields = self.field_keys();
+    let mut known = Vec::new();
+    let mut skipped = Vec::new();
+    for field in patch.child_keys() {
+      let selected = patch.select_fields(std::slice::from_ref(&field));
+      let unknown = match fields.is_empty() {
+        false => !fields.contains(&field),
+        // Without a list of fields, only trust an UnknownKey naming the field itself
+        true => match self.check_patch(&selected) {
+          Ok(()) => false,
+          Err(err) => match err.downcast_ref::<ProteanError>() {
+            Some(ProteanError::UnknownKey(key)) if *key == field => true,
+            _ => return Err(err.context(format!("Could not apply '{}'", field))),
+          },
+        },
+      };
+      match unknown {
+        true => skipped.extend(selected.iter().map(|(key, _)| key.clone())),
+        false => known.push(field),
+      }
+    }
+
+    let mut partial = patch.select_fields(&known);
+    if let Some(op) = patch.get_op(None, "&self") {
+      partial.value_map.insert("&self".to_string(), op.clone());
+    }
+    self
+      .check_patch(&partial)
+      .context("Could not apply the known part of the patch")?;
+    self.apply(&partial)?;
+    skipped.sort();
+    Ok(skipped)
+  }
+
+  /// Apply a patch that the caller has no further use for
+  fn apply_owned(&mut self, patch: Patch) -> Result<()> {
+    self.apply(&patch)
+  }
+
+  /// Check that a patch can be applied, without changing anything
+  ///
+  /// This runs the patch's validator over every change and then makes sure the values fit the type, so
+  /// bad patches can be rejected before anything is touched.
+  fn validate_patch(&self, patch: &Patch) -> Result<()> {
+    patch.validate()?;
+    self.check_patch(patch)
+  }
+
+  /// Make sure every key in the patch exists and every value deserializes into its type
+  ///
+  /// The default applies the patch to a snapshot and throws it away. The derive checks each field in turn
+  /// instead, so the struct is never copied.
+  fn check_patch(&self, patch: &Patch) -> Result<()> {
+    let mut trial = self.snapshot()?;
+    trial.apply(patch)
+  }
+
+  /// A copy that shares no state with the original, so patching one never changes the other
+  ///
+  /// This is the same as clone for most types. Shared state such as an `Arc<Mutex<T>>` clones shallowly,
+  /// so those copy the value they guard into a new lock instead. Everything that patches a copy to try
+  /// a change out or keep the old state around uses this rather than clone.
+  fn snapshot(&self) -> Result<Self>
+  where
+    Self: Sized,
+  {
+    Ok(self.clone())
+  }
+
+  /// Put the value back to a snapshot taken earlier, without replacing any shared state
+  ///
+  /// The default swaps the snapshot in, while shared state copies the snapshot's value into the lock that
+  /// is already there so everyone holding it sees the restored value. The snapshot is left holding
+  /// whatever was replaced.
+  fn restore(&mut self, snapshot: &mut Self)
+  where
+    Self: Sized,
+  {
+    std::mem::swap(self, snapshot)
   }
 
   /// Compare two structs of the same type and return a Patch needed to convert the left to the right
   fn diff(&self, struct2: &SubClass) -> Result<Patch>;
+
+  /// Diff with limits on how far the walk goes
+  ///
+  /// Once max_depth is used up, whatever is left is compared as a whole and replaced with a single
+  /// "&self" change. The default only knows how to do that, so containers override it to pass the
+  /// remaining depth down to their children.
+  fn diff_with_options(&self, struct2: &SubClass, options: &DiffOptions) -> Result<Patch>
+  where
+    SubClass: Serialize,
+  {
+    match options.max_depth {
+      0 => Patch::diff_whole(self.new_patch(), self, struct2),
+      _ => self.diff(struct2),
+    }
+  }
+
+  /// Walk the differences between two structs, handing each changed key to the visitor
+  ///
+  /// This finds the same changes as diff without collecting them into a patch, so each one can be acted
+  /// on as it is discovered. The default falls back to building the full patch, so containers override it
+  /// to keep memory bounded.
+  fn diff_visit(
+    &self,
+    struct2: &SubClass,
+    visitor: &mut dyn FnMut(&str, &PatchOp) -> Result<()>,
+  ) -> Result<()> {
+    for (key, op) in self.diff(struct2)?.iter() {
+      visitor(key, op)?;
+    }
+    Ok(())
+  }
   // fn get_value(&self, key: Option<&str>) -> SubClass;
   // fn set_value(&self, key: Option<&str>, value: String) -> Result<ProteanError>;
 
@@ -120,6 +411,169 @@ pub trait Patchwork<'a, SubClass = Self>: Debug + Clone + Serialize + Deserializ
   }
 }
 
+/// Checks each key/value before it is added to a patch
+///
+/// Validators are shared between patches, so they can carry configuration such as the set of legal key
+/// paths or a schema the values must match.
+pub trait Validator {
+  /// Return an error if the value is not allowed to be stored under the key
+  fn validate(&self, key: &str, value: &serde_json::Value) -> Result<()>;
+
+  /// Whether the key is one the target type knows about
+  ///
+  /// This is only checked by patches with strict keys turned on. The default accepts every key.
+  fn is_valid_key(&self, _key: &str) -> bool {
+    true
+  }
+
+  /// Check a change before it is added to the patch
+  ///
+  /// By default only the value is validated, so removals are always allowed.
+  fn validate_op(&self, key: &str, op: &PatchOp) -> Result<()> {
+    match op.value() {
+      Some(value) => self.validate(key, value),
+      None => Ok(()),
+    }
+  }
+}
+
+/// Use a closure as a Validator
+pub struct FnValidator<F>(F);
+
+impl<F> FnValidator<F>
+where
+  F: Fn(&str, &serde_json::Value) -> Result<()>,
+{
+  pub fn new(validator: F) -> FnValidator<F> {
+    FnValidator(validator)
+  }
+}
+
+impl<F> Validator for FnValidator<F>
+where
+  F: Fn(&str, &serde_json::Value) -> Result<()>,
+{
+  fn validate(&self, key: &str, value: &serde_json::Value) -> Result<()> {
+    (self.0)(key, value)
+  }
+}
+
+/// Accepts the keys of a struct's fields and whatever each field's own validator accepts below them
+///
+/// The derive installs one of these, so strict keys work on derived structs without writing a Validator
+/// by hand. Every value is allowed.
+pub struct FieldValidator {
+  fields: Vec<(String, Rc<dyn Validator>)>,
+  flattened: Vec<Rc<dyn Validator>>,
+}
+
+impl FieldValidator {
+  /// The key and validator of each field, plus the validators of flattened fields which see whole keys
+  pub fn new(
+    fields: Vec<(String, Rc<dyn Validator>)>,
+    flattened: Vec<Rc<dyn Validator>>,
+  ) -> FieldValidator {
+    FieldValidator { fields, flattened }
+  }
+}
+
+impl Validator for FieldValidator {
+  fn validate(&self, _key: &str, _value: &serde_json::Value) -> Result<()> {
+    Ok(())
+  }
+
+  fn is_valid_key(&self, key: &str) -> bool {
+    if key == "&self" {
+      return true;
+    }
+    let (field, nested) = match key.find('.') {
+      Some(position) => (&key[..position], Some(&key[position + 1..])),
+      None => (key, None),
+    };
+    let known = self.fields.iter().any(|(name, validator)| {
+      name == field && nested.map_or(true, |nested| validator.is_valid_key(nested))
+    });
+    known
+      || self
+        .flattened
+        .iter()
+        .any(|validator| validator.is_valid_key(key))
+  }
+}
+
+/// Settings for Patchwork::diff_with_options
+///
+/// The default is unbounded, which gives the same result as diff
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiffOptions {
+  /// How many levels below the root can be descended into. At zero, the value is compared as a whole.
+  pub max_depth: usize,
+}
+
+impl Default for DiffOptions {
+  fn default() -> DiffOptions {
+    DiffOptions {
+      max_depth: usize::MAX,
+    }
+  }
+}
+
+impl DiffOptions {
+  /// The options to use for the children of the current value
+  pub fn descend(&self) -> DiffOptions {
+    DiffOptions {
+      max_depth: self.max_depth.saturating_sub(1),
+    }
+  }
+}
+
+/// A single change stored in a patch
+///
+/// Removal is its own operation, so a value being set to null can be told apart from a value being taken
+/// out of its container. Add is used for values that did not exist before, like a new element in a vec.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PatchOp {
+  /// A new value, that didn't exist in the original
+  Add(serde_json::Value),
+
+  /// Replace the existing value
+  Set(serde_json::Value),
+
+  /// Take the value out of its parent (vec element, map entry, etc.)
+  Remove,
+}
+
+impl PatchOp {
+  /// The value being stored, or None for a removal
+  pub fn value(&self) -> Option<&serde_json::Value> {
+    match self {
+      PatchOp::Add(value) | PatchOp::Set(value) => Some(value),
+      PatchOp::Remove => None,
+    }
+  }
+}
+
+/// A single change with the values from before and after it, ready to be published
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+  /// The patch key that changed
+  pub path: String,
+
+  /// The value before the change, or None if it was just added
+  pub old: Option<serde_json::Value>,
+
+  /// The value after the change, or None if it was removed
+  pub new: Option<serde_json::Value>,
+}
+
+/// How many keys under a field were changed by each kind of operation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeCounts {
+  pub added: usize,
+  pub modified: usize,
+  pub removed: usize,
+}
+
 /// A container for managing a set of changes to a given implementation of Patchwork
 #[derive(Clone)]
 pub struct Patch {
@@ -133,20 +587,51 @@ pub struct Patch {
   /// This should be generated using Hash on the base object
   key: Option<u64>,
 
-  /// A validating closure that ensures that only
-  validator: Rc<dyn Fn(String, serde_json::Value) -> Result<()>>,
+  /// Ensures that only valid key/values are added to the patch
+  validator: Rc<dyn Validator>,
 
   /// The map is so we can gather a bulk update.
   ///
   /// The key is the location of the value within the object encoded in dot notation.
   /// THINK: diff of HashMap where the key is not a primitive?
   /// THINK: Considering just using serde_json and having the accessor be
-  value_map: HashMap<String, serde_json::Value>,
+  value_map: ValueMap,
+
+  /// The version each key was last changed in, for the keys that have been stamped
+  ///
+  /// This is only ever read through the keys in value_map, so entries left behind by a removed key are
+  /// harmless.
+  versions: HashMap<String, u64>,
+
+  /// Reject keys the validator doesn't know about as soon as they are added
+  strict_keys: bool,
+
+  /// A validator that can wait on outside checks, only run by AsyncPatchwork::apply_async
+  #[cfg(feature = "async")]
+  async_validator: Option<Rc<dyn AsyncValidator>>,
 }
 
+/// A human readable rendering of the patch, similar to unified diff
+///
+/// Each changed key is written on its own line, sorted by key so the output is stable. Modifications are
+/// marked with `~`, additions with `+` and removals with `-`:
+/// ```text
+/// ~ address.city: "Portland"
+/// + emails.2: "dave@example.com"
+/// - phones.1
+/// ```
 impl std::fmt::Display for Patch {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{:#?}", self)
+    let mut entries: Vec<(&String, &PatchOp)> = self.value_map.iter().collect();
+    entries.sort_by_key(|(key, _)| *key);
+    for (key, op) in entries {
+      match op {
+        PatchOp::Add(value) => writeln!(f, "+ {}: {}", key, value)?,
+        PatchOp::Set(value) => writeln!(f, "~ {}: {}", key, value)?,
+        PatchOp::Remove => writeln!(f, "- {}", key)?,
+      }
+    }
+    Ok(())
   }
 }
 
@@ -156,136 +641,2317 @@ impl std::fmt::Debug for Patch {
   }
 }
 
+/// Patches are equal when they have the same type and the same changes
+///
+/// The validator is a trait object and can't be compared, so it is ignored. Versions only say when the
+/// changes were made, so they are ignored as well.
+impl PartialEq for Patch {
+  fn eq(&self, other: &Patch) -> bool {
+    self.patch_type == other.patch_type && self.value_map == other.value_map
+  }
+}
+
+impl Eq for Patch {}
+
+/// Hashes the same fields as PartialEq
+///
+/// The value map is walked in key order so equal patches always hash the same. JSON values don't
+/// implement Hash, so each change is hashed as its serialized string.
+impl std::hash::Hash for Patch {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.patch_type.hash(state);
+    let mut entries: Vec<(&String, &PatchOp)> = self.value_map.iter().collect();
+    entries.sort_by_key(|(key, _)| *key);
+    for (key, op) in entries {
+      key.hash(state);
+      serde_json::to_string(op).unwrap_or_default().hash(state);
+    }
+  }
+}
+
+/// Converts with Patch::to_value, for handing a patch to a JSON based transport
+impl std::convert::TryFrom<Patch> for serde_json::Value {
+  type Error = anyhow::Error;
+
+  fn try_from(patch: Patch) -> Result<serde_json::Value> {
+    patch.to_value()
+  }
+}
+
+impl std::convert::TryFrom<&Patch> for serde_json::Value {
+  type Error = anyhow::Error;
+
+  fn try_from(patch: &Patch) -> Result<serde_json::Value> {
+    patch.to_value()
+  }
+}
+
+/// Converts with Patch::from_value, so bad input is a MalformedPatch
+///
+/// The patch comes back with a validator that allows everything. Swap it with with_validator before
+/// adding anything else to it.
+impl std::convert::TryFrom<serde_json::Value> for Patch {
+  type Error = anyhow::Error;
+
+  fn try_from(value: serde_json::Value) -> Result<Patch> {
+    Patch::from_value(value)
+  }
+}
+
+impl std::convert::TryFrom<&serde_json::Value> for Patch {
+  type Error = anyhow::Error;
+
+  fn try_from(value: &serde_json::Value) -> Result<Patch> {
+    Patch::from_value(value.clone())
+  }
+}
+
 impl Patch {
-  /// Add a new record to the patch
+  /// Create an empty patch for the named type that accepts any key/value
+  pub fn new(patch_type: &str) -> Patch {
+    Patch {
+      patch_type: patch_type.to_string(),
+      key: None,
+      validator: Rc::new(FnValidator::new(|_key, _value| Ok(()))),
+      value_map: ValueMap::new(),
+      versions: HashMap::new(),
+      strict_keys: false,
+      #[cfg(feature = "async")]
+      async_validator: None,
+    }
+  }
+
+  /// The name of the type the patch was made for
+  pub fn patch_type(&self) -> &str {
+    &self.patch_type
+  }
+
+  /// Add a new record to the patch, replacing the value at the key
   pub fn add(&mut self, key: &String, value: &serde_json::Value) -> Result<Patch> {
-    let validator = &self.validator;
-    validator(key.clone(), value.clone())?;
-    self.value_map.insert(key.clone(), value.clone());
+    self.put(key.clone(), PatchOp::Set(value.clone()))?;
     Ok(self.clone())
   }
 
-  /// Combine two
-  pub fn merge(&mut self, prefix: &str, patch: Patch) -> Result<Patch> {
-    patch
-      .value_map
-      .iter()
-      .fold(Ok(self.clone()), |acc, (k, v)| {
-        // THINK: Does this need to be optimized to get rid of the validator?
-        let key = match &k[..] {
-          "&self" => prefix.to_string(),
-          _ => format!("{}.{}", prefix, k),
-        };
-        acc?.add(&key, &v)
-      })
+  /// Record a value that didn't exist before, such as a new element in a vec
+  pub fn insert(&mut self, key: &str, value: &serde_json::Value) -> Result<&mut Patch> {
+    self.put(key.to_string(), PatchOp::Add(value.clone()))?;
+    Ok(self)
   }
 
-  /// Checks to see if the patch has any values stored in it
-  pub fn is_empty(&self) -> bool {
-    self.value_map.is_empty()
+  /// Record that the value at the key was taken out of its container
+  pub fn remove(&mut self, key: &str) -> Result<&mut Patch> {
+    self.put(key.to_string(), PatchOp::Remove)?;
+    Ok(self)
   }
 
-  /// Getter for values in the patch
-  pub fn get(&self, prefix: Option<String>, key: &str) -> Option<&serde_json::Value> {
-    let mut path = prefix.map_or("".to_string(), |x| format!("{}.", x));
-    path.push_str(key);
-    self.value_map.get(&path)
+  /// Run the validator over every change in the patch
+  pub fn validate(&self) -> Result<()> {
+    for (key, op) in self.value_map.iter() {
+      self.check_key(key)?;
+      self.validator.validate_op(key, op)?;
+    }
+    Ok(())
   }
 
-  /// Getter for the key
-  pub fn get_key(&self) -> Result<u64> {
-    match self.key {
-      Some(key) => Ok(key.clone()),
-      None => Err(ProteanError::NoKeySet).context("Ran get_key but got None"),
+  /// Validate and store a single change
+  fn put(&mut self, key: String, op: PatchOp) -> Result<()> {
+    self.check_key(&key)?;
+    self.validator.validate_op(&key, &op)?;
+    // Any version belonged to the change being replaced
+    self.versions.remove(&key);
+    self.value_map.insert(key, op);
+    Ok(())
+  }
+
+  /// Mark every key in the patch as changed in the given version
+  pub fn stamp(&mut self, version: u64) -> &mut Patch {
+    for key in self.value_map.keys() {
+      self.versions.insert(key.clone(), version);
     }
+    self
   }
 
-  pub fn set_key(&self, key_hash: u64) -> Result<Patch> {
-    Ok(Patch {
-      key: Some(key_hash),
-      ..self.clone()
-    })
+  /// The version the key was last changed in, if it has been stamped
+  pub fn version(&self, key: &str) -> Option<u64> {
+    match self.value_map.get(key) {
+      Some(_) => self.versions.get(key).copied(),
+      None => None,
+    }
   }
 
-  // --------  Static helpers
+  /// Keep only the keys changed after the given version
+  ///
+  /// Keys that were never stamped can't be proven old, so they are kept.
+  pub fn retain_changed_since(&self, version: u64) -> Patch {
+    let mut retained = self.empty_like();
+    for (key, op) in self.value_map.iter() {
+      let stamped = self.versions.get(key).copied();
+      if stamped.map_or(true, |stamped| stamped > version) {
+        retained.value_map.insert(key.clone(), op.clone());
+        if let Some(stamped) = stamped {
+          retained.versions.insert(key.clone(), stamped);
+        }
+      }
+    }
+    retained
+  }
 
-  /// Convert a patch to its original type
+  /// Add the changes made to a flattened field at the top level, without a prefix
   ///
-  /// This assumes there is enough data in the patch for all the non-optional values. Essentially,
-  /// this is a serialized form with all the unset optional fields removed.
-  /// THINK:
-  /// - Add coerce option which ignores the type?
-  pub fn from_patch<'a, T>(prefix: Option<String>, patch: &Patch) -> Result<T>
-  where
-    T: Patchwork<'a>,
-  {
-    T::from_patch(prefix, &patch).context("Could not create a Test Object from patch")
+  /// The keys are expected to be unique, so one that is already in the patch is a DuplicateKey rather
+  /// than an overwrite. A change to the whole field can't be flattened, since it has no key of its own.
+  pub fn merge_flattened(&mut self, sub: Patch) -> Result<&mut Patch> {
+    for (key, op) in sub.value_map.into_iter() {
+      if key == "&self" {
+        return Err(ProteanError::UnknownKey(key))
+          .context("A flattened field must be diffed into its own keys");
+      }
+      if self.value_map.keys().any(|existing| {
+        *existing == key || is_ancestor(existing, &key) || is_ancestor(&key, existing)
+      }) {
+        return Err(ProteanError::DuplicateKey(key))
+          .context("A flattened field shares a key with another field");
+      }
+      self.put(key.clone(), op)?;
+      if let Some(version) = sub.versions.get(&key) {
+        self.versions.insert(key, *version);
+      }
+    }
+    Ok(self)
   }
-}
 
-//****************************************   Primitive Type Implementations ********************************/
-/// Implement all the primitives with a common set of code.
-///
-/// These are types of values that simple equality works for. String is included, as we are looking at it
-/// holistically and not as an array of characters
-macro_rules! primitive_patchwork {
-  ($type:ty) => {
-    impl<'a> Patchwork<'a> for $type {
-      /// ```
-      /// let i = 10;
-      /// let patch = i.diff(10)
-      /// ```
-      fn diff(&self, struct2: &$type) -> Result<Patch> {
-        let mut patch = self.new_patch();
-        log::debug!("self: {:#?}, struct2: {:#?}", &self, struct2);
-        if self != struct2 {
-          patch.add(&"&self".to_string(), &serde_json::to_value(struct2)?)?;
+  /// Keep only the keys that fall under one of the given fields
+  ///
+  /// This is the flattened counterpart of strip_prefix, handing a flattened field the part of the patch
+  /// that belongs to it with the keys left as they are.
+  pub fn select_fields(&self, fields: &[String]) -> Patch {
+    let mut selected = self.empty_like();
+    for (key, op) in self.value_map.iter() {
+      let field = key.split('.').next().unwrap_or_default();
+      if key != "&self" && fields.iter().any(|known| known == field) {
+        selected.value_map.insert(key.clone(), op.clone());
+        if let Some(version) = self.versions.get(key) {
+          selected.versions.insert(key.clone(), *version);
         }
-        Ok(patch)
       }
+    }
+    selected
+  }
+
+  /// Combine two
+  ///
+  /// Returns a copy of this patch with the other merged in under the prefix, leaving self untouched
+  pub fn merge(&mut self, prefix: &str, patch: Patch) -> Result<Patch> {
+    let mut merged = self.clone();
+    merged.merge_field(prefix, patch)?;
+    Ok(merged)
+  }
+
+  /// Add the changes made to a field under the field's key
+  ///
+  /// This is the building block for diffing a struct: diff each field and merge the resulting patch in
+  /// using the field's name. Calls can be chained:
+  /// ```ignore
+  /// patch
+  ///   .merge_field("name", self.name.diff(&other.name)?)?
+  ///   .merge_field("age", self.age.diff(&other.age)?)?;
+  /// ```
+  pub fn merge_field(&mut self, field: &str, sub: Patch) -> Result<&mut Patch> {
+    for (key, op) in sub.value_map.into_iter() {
+      // THINK: Does this need to be optimized to get rid of the validator?
+      let prefixed = Patch::prefix_key(field, &key);
+      self.put(prefixed.clone(), op)?;
+      if let Some(version) = sub.versions.get(&key) {
+        self.versions.insert(prefixed, *version);
+      }
+    }
+    Ok(self)
+  }
+
+  /// Merge the patches of several fields at once
+  pub fn merge_fields<I, S>(&mut self, fields: I) -> Result<&mut Patch>
+  where
+    I: IntoIterator<Item = (S, Patch)>,
+    S: AsRef<str>,
+  {
+    for (field, sub) in fields {
+      self.merge_field(field.as_ref(), sub)?;
+    }
+    Ok(self)
+  }
+
+  /// Combine two patches into one, with the same effect as applying self and then later
+  ///
+  /// Later changes win. A key replaced by the later patch also drops everything nested below it, and a
+  /// value added then removed again cancels out entirely. A later change nested inside of a value the
+  /// first patch added or set is folded into that value, since apply doesn't look below it. The added and
+  /// removed lists of a BTreeSet are merged rather than replaced.
+  pub fn compose(&self, later: &Patch) -> Result<Patch> {
+    let mut composed = self.clone();
+    let mut sets = BTreeSet::new();
+    for (key, op) in later.iter() {
+      let (parent, last) = split_key(key);
+      if last == "added" || last == "removed" {
+        // Both lists are merged together the first time the set is seen
+        if sets.contains(parent) {
+          continue;
+        }
+        if later.is_set_change(parent) && composed.is_set_change(parent) {
+          sets.insert(parent.to_string());
+          composed.compose_set(later, parent)?;
+          continue;
+        }
+      }
+
+      let outer = composed
+        .value_map
+        .iter()
+        .filter(|(existing, op)| is_ancestor(existing, key) && op.value().is_some())
+        .map(|(existing, op)| (existing.clone(), op.clone()))
+        .min_by_key(|(existing, _)| existing.len());
+      if let Some((outer, mut folded)) = outer {
+        if let PatchOp::Add(value) | PatchOp::Set(value) = &mut folded {
+          let nested = match outer.as_str() {
+            "&self" => key.to_string(),
+            _ => key[outer.len() + 1..].to_string(),
+          };
+          fold_json(value, &nested, op).context(format!(
+            "Could not fold '{}' into the value at '{}'",
+            key, outer
+          ))?;
+        }
+        composed.put(outer.clone(), folded)?;
+        if let Some(version) = later.versions.get(key) {
+          composed.versions.insert(outer, *version);
+        }
+        continue;
+      }
+
+      composed
+        .value_map
+        .retain(|existing, _| !is_ancestor(key, existing));
+      let existing = composed.value_map.get(key).cloned();
+      let op = match (existing, op) {
+        // It never existed before the first patch, so there is nothing left to do
+        (Some(PatchOp::Add(_)), PatchOp::Remove) => {
+          composed.value_map.remove(key);
+          composed.versions.remove(key);
+          continue;
+        }
+        // It existed before the first patch and still does, so it is just a change
+        (Some(PatchOp::Remove), PatchOp::Add(value)) => PatchOp::Set(value.clone()),
+        // Still hasn't been seen by the original, so it has to stay an addition
+        (Some(PatchOp::Add(_)), PatchOp::Set(value)) => PatchOp::Add(value.clone()),
+        _ => op.clone(),
+      };
+      composed.put(key.clone(), op)?;
+      if let Some(version) = later.versions.get(key) {
+        composed.versions.insert(key.clone(), *version);
+      }
+    }
+    Ok(composed)
+  }
+
+  /// Whether the changes under the parent are the added and removed lists of a BTreeSet
+  fn is_set_change(&self, parent: &str) -> bool {
+    ["added", "removed"].iter().any(|last| {
+      matches!(
+        self.value_map.get(&join_key(parent, last)),
+        Some(PatchOp::Add(serde_json::Value::Array(_)))
+      )
+    })
+  }
+
+  /// Merge the BTreeSet changes under the parent from the later patch into this one
+  ///
+  /// Elements removed by the later patch are no longer added, and elements the first patch added were
+  /// never in the original set so there is no need to remove them.
+  fn compose_set(&mut self, later: &Patch, parent: &str) -> Result<()> {
+    let list = |patch: &Patch, last: &str| match patch.value_map.get(&join_key(parent, last)) {
+      Some(PatchOp::Add(serde_json::Value::Array(items))) => items.clone(),
+      _ => Vec::new(),
+    };
+    let (earlier_added, earlier_removed) = (list(self, "added"), list(self, "removed"));
+    let (later_added, later_removed) = (list(later, "added"), list(later, "removed"));
+
+    let mut added: Vec<serde_json::Value> = earlier_added
+      .iter()
+      .filter(|item| !later_removed.contains(item))
+      .cloned()
+      .collect();
+    for item in later_added {
+      if !added.contains(&item) {
+        added.push(item);
+      }
+    }
+    let mut removed = earlier_removed;
+    for item in later_removed {
+      if !earlier_added.contains(&item) && !removed.contains(&item) {
+        removed.push(item);
+      }
+    }
+    removed.retain(|item| !added.contains(item));
+
+    for (last, items) in [("added", added), ("removed", removed)] {
+      let key = join_key(parent, last);
+      self.versions.remove(&key);
+      match items.is_empty() {
+        true => {
+          self.value_map.remove(&key);
+        }
+        false => {
+          self.put(key.clone(), PatchOp::Add(serde_json::Value::Array(items)))?;
+          if let Some(version) = later.versions.get(&key) {
+            self.versions.insert(key, *version);
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Namespace every key in the patch under the prefix
+  ///
+  /// This is the inverse of strip_prefix, so a patch built against a sub-struct can be embedded in its
+  /// parent. `&self` becomes the prefix and every other key `k` becomes `prefix.k`.
+  pub fn prefix_keys(&self, prefix: &str) -> Patch {
+    let value_map = self
+      .value_map
+      .iter()
+      .map(|(k, v)| (Patch::prefix_key(prefix, k), v.clone()))
+      .collect();
+    let versions = self
+      .versions
+      .iter()
+      .map(|(k, v)| (Patch::prefix_key(prefix, k), *v))
+      .collect();
+
+    Patch {
+      value_map,
+      versions,
+      ..self.clone()
+    }
+  }
+
+  /// The number of keys in the patch
+  pub fn len(&self) -> usize {
+    self.value_map.len()
+  }
+
+  /// Checks to see if the patch has any values stored in it
+  pub fn is_empty(&self) -> bool {
+    self.value_map.is_empty()
+  }
+
+  /// Getter for values in the patch
+  ///
+  /// Removals have no value, so use get_op to tell them apart from a missing key
+  pub fn get(&self, prefix: Option<String>, key: &str) -> Option<&serde_json::Value> {
+    self.get_op(prefix, key).and_then(|op| op.value())
+  }
+
+  /// Getter for the change stored in the patch
+  pub fn get_op(&self, prefix: Option<String>, key: &str) -> Option<&PatchOp> {
+    let mut path = prefix.map_or("".to_string(), |x| format!("{}.", x));
+    path.push_str(key);
+    self.value_map.get(&path)
+  }
+
+  /// Iterate over all the key/change pairs stored in the patch
+  pub fn iter(&self) -> impl Iterator<Item = (&String, &PatchOp)> {
+    self.value_map.iter()
+  }
+
+  /// List the first segment of every key in the patch, excluding `&self`
+  ///
+  /// For a struct these are the names of the fields being patched, and for a vec the indices. The result
+  /// is sorted and contains no duplicates.
+  pub fn child_keys(&self) -> Vec<String> {
+    let mut children: Vec<String> = self
+      .value_map
+      .keys()
+      .filter(|k| *k != "&self")
+      .map(|k| k.split('.').next().unwrap_or(k).to_string())
+      .collect();
+    children.sort();
+    children.dedup();
+    children
+  }
+
+  /// Create a sub-patch containing only the keys found under the prefix
+  ///
+  /// This is the reverse of merge, used to hand the portion of a patch that belongs to a field down to
+  /// that field's apply. A key matching the prefix exactly becomes `&self` and the prefix is removed
+  /// from every key nested below it. The patch type and validator are copied from the parent.
+  pub fn strip_prefix(&self, prefix: &str) -> Patch {
+    let nested = format!("{}.", prefix);
+    let strip = |k: &String| match k == prefix {
+      true => Some("&self".to_string()),
+      false => k.strip_prefix(&nested).map(|rest| rest.to_string()),
+    };
+    let value_map = self
+      .value_map
+      .iter()
+      .filter_map(|(k, v)| strip(k).map(|k| (k, v.clone())))
+      .collect();
+    let versions = self
+      .versions
+      .iter()
+      .filter_map(|(k, v)| strip(k).map(|k| (k, *v)))
+      .collect();
+
+    Patch {
+      value_map,
+      versions,
+      ..self.clone()
+    }
+  }
+
+  /// List the keys where both patches make incompatible changes
+  ///
+  /// A conflict is either the same key being given different changes, or one patch replacing a value
+  /// that the other reaches inside of (eg. `address` vs `address.city`). In the second case the broader
+  /// key is reported. The result is sorted and contains no duplicates.
+  pub fn conflicts_with(&self, other: &Patch) -> Vec<String> {
+    let mut conflicts = Vec::new();
+    for (left, left_op) in self.value_map.iter() {
+      for (right, right_op) in other.value_map.iter() {
+        if left == right {
+          if left_op != right_op {
+            conflicts.push(left.clone());
+          }
+        } else if is_ancestor(left, right) {
+          conflicts.push(left.clone());
+        } else if is_ancestor(right, left) {
+          conflicts.push(right.clone());
+        }
+      }
+    }
+    conflicts.sort();
+    conflicts.dedup();
+    conflicts
+  }
+
+  /// Compare two values by their serialized form, replacing the whole value if they differ
+  ///
+  /// This is what diff_with_options falls back to once it runs out of depth
+  pub fn diff_whole<L, R>(mut patch: Patch, left: &L, right: &R) -> Result<Patch>
+  where
+    L: Serialize + ?Sized,
+    R: Serialize + ?Sized,
+  {
+    let right = serde_json::to_value(right)?;
+    if serde_json::to_value(left)? != right {
+      patch.add(&"&self".to_string(), &right)?;
+    }
+    Ok(patch)
+  }
+
+  /// Getter for the key
+  pub fn get_key(&self) -> Result<u64> {
+    match self.key {
+      Some(key) => Ok(key.clone()),
+      None => Err(ProteanError::NoKeySet).context("Ran get_key but got None"),
+    }
+  }
+
+  /// The validator used when adding new values
+  pub fn validator(&self) -> Rc<dyn Validator> {
+    self.validator.clone()
+  }
+
+  /// Replace the validator used when adding new values
+  pub fn with_validator(&self, validator: Rc<dyn Validator>) -> Patch {
+    Patch {
+      validator,
+      ..self.clone()
+    }
+  }
+
+  /// Turn the validator's key check on or off
+  ///
+  /// Strict patches return UnknownKey as soon as a key the validator doesn't recognize is added, which
+  /// suits patches built by trusted code. Patches are lenient by default, including those read from JSON,
+  /// so unknown keys from a newer sender are kept until apply decides what to do with them.
+  pub fn with_strict_keys(&self, strict: bool) -> Patch {
+    Patch {
+      strict_keys: strict,
+      ..self.clone()
+    }
+  }
+
+  /// Whether the validator's key check is turned on
+  pub fn has_strict_keys(&self) -> bool {
+    self.strict_keys
+  }
+
+  /// Fail on a key the validator doesn't know when strict keys are turned on
+  fn check_key(&self, key: &str) -> Result<()> {
+    match self.strict_keys && !self.validator.is_valid_key(key) {
+      true => Err(ProteanError::UnknownKey(key.to_string()))
+        .context("The patch only accepts keys its validator knows about"),
+      false => Ok(()),
+    }
+  }
+
+  pub fn set_key(&self, key_hash: u64) -> Result<Patch> {
+    Ok(Patch {
+      key: Some(key_hash),
+      ..self.clone()
+    })
+  }
+
+  /// Convert the patch into a JSON value
+  ///
+  /// The changes are already stored as JSON values, so they nest directly into the result rather than
+  /// being encoded as strings. The validator can't be serialized, so only the patch type and the changes
+  /// are included: `{"patch_type": "...", "value_map": {"key": {"Set": value}, "other": "Remove"}}`
+  ///
+  /// Stamped patches also carry a `"versions": {"key": 3}` object for the keys that have one.
+  pub fn to_value(&self) -> Result<serde_json::Value> {
+    let mut value = serde_json::json!({
+      "patch_type": self.patch_type,
+      "value_map": serde_json::to_value(&self.value_map)?,
+    });
+    let versions: serde_json::Map<String, serde_json::Value> = self
+      .value_map
+      .keys()
+      .filter_map(|key| self.versions.get(key).map(|v| (key.clone(), (*v).into())))
+      .collect();
+    if !versions.is_empty() {
+      value["versions"] = serde_json::Value::Object(versions);
+    }
+    Ok(value)
+  }
+
+  /// Read a patch from the JSON value made by to_value
+  ///
+  /// The value is usually untrusted, so its shape is checked before anything is built and any problem is
+  /// returned as a MalformedPatch. The validator isn't part of the JSON, so the patch comes back with
+  /// one that allows everything. Use with_validator or Patchwork::validate_patch before trusting what
+  /// was received.
+  pub fn from_value(value: serde_json::Value) -> Result<Patch> {
+    let malformed =
+      |reason: String| -> anyhow::Error { ProteanError::MalformedPatch(reason).into() };
+
+    let mut fields = match value {
+      serde_json::Value::Object(fields) => fields,
+      other => return Err(malformed(format!("expected an object, found {}", other))),
+    };
+    if let Some(unknown) = fields
+      .keys()
+      .find(|key| *key != "patch_type" && *key != "value_map" && *key != "versions")
+    {
+      return Err(malformed(format!("unexpected field '{}'", unknown)));
+    }
+
+    let patch_type = match fields.remove("patch_type") {
+      Some(serde_json::Value::String(patch_type)) => patch_type,
+      Some(_) => return Err(malformed("patch_type must be a string".to_string())),
+      None => return Err(malformed("missing patch_type".to_string())),
+    };
+    let entries = match fields.remove("value_map") {
+      Some(serde_json::Value::Object(entries)) => entries,
+      Some(_) => return Err(malformed("value_map must be an object".to_string())),
+      None => return Err(malformed("missing value_map".to_string())),
+    };
+    let versions = match fields.remove("versions") {
+      Some(serde_json::Value::Object(versions)) => versions,
+      Some(_) => return Err(malformed("versions must be an object".to_string())),
+      None => serde_json::Map::new(),
+    };
+
+    let mut patch = Patch::new(&patch_type);
+    for (key, op) in entries {
+      if key != "&self"
+        && key
+          .split('.')
+          .any(|segment| segment.is_empty() || segment == "&self")
+      {
+        return Err(malformed(format!("'{}' is not a valid key", key)));
+      }
+      let op: PatchOp = serde_json::from_value(op)
+        .map_err(|err| malformed(format!("the change at '{}' is invalid: {}", key, err)))?;
+      patch.value_map.insert(key, op);
+    }
+    for (key, version) in versions {
+      if patch.value_map.get(&key).is_none() {
+        return Err(malformed(format!("'{}' has a version but no change", key)));
+      }
+      let version = version
+        .as_u64()
+        .ok_or_else(|| malformed(format!("the version of '{}' must be an integer", key)))?;
+      patch.versions.insert(key, version);
+    }
+    Ok(patch)
+  }
+
+  /// Serialize the patch so it can be sent to another process
+  pub fn to_json(&self) -> Result<String> {
+    Ok(serde_json::to_string(&self.to_value()?)?)
+  }
+
+  /// Read a patch written by to_json
+  pub fn from_json(json: &str) -> Result<Patch> {
+    let value = serde_json::from_str(json)
+      .map_err(|err| ProteanError::MalformedPatch(format!("invalid JSON: {}", err)))?;
+    Patch::from_value(value)
+  }
+
+  /// A cheap upper bound on the number of bytes to_json will produce
+  ///
+  /// This walks the values rather than serializing them, so it can be used to pack patches into fixed
+  /// size batches. It may over count slightly but will never be smaller than the serialized patch.
+  pub fn byte_size(&self) -> usize {
+    // The fixed parts: {"patch_type":,"value_map":{}}
+    let overhead = 30 + json_string_size(&self.patch_type);
+    let size = self.value_map.iter().fold(overhead, |size, (key, op)| {
+      // Each entry adds a colon and at most one comma
+      size + json_string_size(key) + json_op_size(op) + 2
+    });
+    // ,"versions":{} followed by the key, a colon, a u64 and a comma for each stamped key
+    let stamped: Vec<&String> = self
+      .value_map
+      .keys()
+      .filter(|key| self.versions.contains_key(*key))
+      .collect();
+    match stamped.is_empty() {
+      true => size,
+      false => stamped
+        .iter()
+        .fold(size + 14, |size, key| size + json_string_size(key) + 22),
+    }
+  }
+
+  /// Apply the patch straight to a serialized value, without needing the type it came from
+  ///
+  /// Each key is walked down the JSON one segment at a time, creating objects for any that are missing
+  /// along the way. Array elements are addressed by index, with an Add one past the end pushing onto the
+  /// array in the same way as Vec. Keys are applied as written, so types using their own key scheme such
+  /// as BTreeSet won't come out the same as applying the patch to the type itself.
+  ///
+  /// Like try_apply_all, the value is only changed when every key applies.
+  pub fn apply_to_json(&self, value: &mut serde_json::Value) -> Result<()> {
+    let mut entries: Vec<(&String, &PatchOp)> = self.value_map.iter().collect();
+    entries.sort_by(|(left, _), (right, _)| compare_keys(left, right));
+
+    // Removals go last and from the back, so array indices stay valid while the rest are applied
+    let (removals, changes): (Vec<_>, Vec<_>) = entries
+      .into_iter()
+      .partition(|(_, op)| *op == &PatchOp::Remove);
+
+    let mut patched = value.clone();
+    for (key, op) in changes {
+      let op_value = op.value().cloned().unwrap_or(serde_json::Value::Null);
+      if key == "&self" {
+        patched = op_value;
+        continue;
+      }
+      let (parent, last) = split_key(key);
+      let node = json_node_mut(&mut patched, parent, true)?
+        .ok_or_else(|| ProteanError::MissingValue(key.clone()))?;
+      if let serde_json::Value::Array(items) = node {
+        let index = json_index(key, last)?;
+        match (index == items.len(), op) {
+          (true, PatchOp::Add(_)) => items.push(op_value),
+          _ => {
+            *items
+              .get_mut(index)
+              .ok_or_else(|| ProteanError::MissingValue(key.clone()))
+              .context("Only an Add can extend an array, and only at its end")? = op_value
+          }
+        }
+        continue;
+      }
+      if !node.is_object() {
+        *node = serde_json::Value::Object(serde_json::Map::new());
+      }
+      if let serde_json::Value::Object(fields) = node {
+        fields.insert(last.to_string(), op_value);
+      }
+    }
+
+    for (key, _) in removals.into_iter().rev() {
+      if key == "&self" {
+        return Err(ProteanError::CannotRemove(key.clone()).into());
+      }
+      let (parent, last) = split_key(key);
+      let removed = match json_node_mut(&mut patched, parent, false)? {
+        Some(serde_json::Value::Array(items)) => {
+          let index = json_index(key, last)?;
+          match index < items.len() {
+            true => Some(items.remove(index)),
+            false => None,
+          }
+        }
+        Some(serde_json::Value::Object(fields)) => fields.remove(last),
+        _ => None,
+      };
+      if removed.is_none() {
+        return Err(ProteanError::MissingValue(key.clone()))
+          .context("Cannot remove a value that isn't in the JSON");
+      }
+    }
+
+    *value = patched;
+    Ok(())
+  }
+
+  /// Map each key in the patch to its RFC 6901 JSON Pointer
+  ///
+  /// Each segment becomes a reference token with `~` and `/` escaped, and "&self" becomes the empty
+  /// pointer that refers to the whole document. List indices are already array positions.
+  pub fn json_pointer_keys(&self) -> HashMap<String, String> {
+    self
+      .value_map
+      .keys()
+      .map(|key| (key.clone(), json_pointer(key)))
+      .collect()
+  }
+
+  /// Convert the patch into RFC 6902 JSON Patch operations
+  ///
+  /// Add, Set and Remove become add, replace and remove. The operations are ordered the same way
+  /// apply_to_json applies them, so removals come last and from the back, and the list can be handed to
+  /// any JSON Patch library that applies operations in order.
+  pub fn to_json_patch(&self) -> Vec<serde_json::Value> {
+    let mut entries: Vec<(&String, &PatchOp)> = self.value_map.iter().collect();
+    entries.sort_by(|(left, _), (right, _)| compare_keys(left, right));
+    let (removals, changes): (Vec<_>, Vec<_>) = entries
+      .into_iter()
+      .partition(|(_, op)| *op == &PatchOp::Remove);
+
+    let operation = |key: &String, op: &PatchOp| {
+      let path = json_pointer(key);
+      match op {
+        // The root always exists, so adding to it replaces it
+        PatchOp::Add(value) if key != "&self" => {
+          serde_json::json!({ "op": "add", "path": path, "value": value })
+        }
+        PatchOp::Add(value) | PatchOp::Set(value) => {
+          serde_json::json!({ "op": "replace", "path": path, "value": value })
+        }
+        PatchOp::Remove => serde_json::json!({ "op": "remove", "path": path }),
+      }
+    };
+    changes
+      .into_iter()
+      .chain(removals.into_iter().rev())
+      .map(|(key, op)| operation(key, op))
+      .collect()
+  }
+
+  /// Apply every key in the patch to the target, or none of them
+  ///
+  /// A snapshot of the target is taken first and restored if any key fails to apply, so on error the
+  /// target is left exactly as it was.
+  pub fn try_apply_all<'a, T>(&self, target: &mut T) -> Result<()>
+  where
+    T: Patchwork<'a>,
+  {
+    let mut snapshot = target.snapshot()?;
+    target.apply(self).map_err(|err| {
+      target.restore(&mut snapshot);
+      err.context("Rolled back the partially applied patch")
+    })
+  }
+
+  /// Count the changes under each top level field, split by operation
+  ///
+  /// Keys are grouped by their first segment, so a change to the whole struct is counted under "&self".
+  pub fn diff_summary(&self) -> HashMap<String, ChangeCounts> {
+    let mut summary: HashMap<String, ChangeCounts> = HashMap::new();
+    for (key, op) in self.value_map.iter() {
+      let field = key.split('.').next().unwrap_or_default();
+      let counts = summary.entry(field.to_string()).or_default();
+      match op {
+        PatchOp::Add(_) => counts.added += 1,
+        PatchOp::Set(_) => counts.modified += 1,
+        PatchOp::Remove => counts.removed += 1,
+      }
+    }
+    summary
+  }
+
+  /// Pair each change with the value it replaces in the base
+  ///
+  /// The patched base is diffed back against the original, stopping at the depth of each key, so the
+  /// old values come out under the same keys as the patch even for renamed and flattened fields. A key
+  /// whose old value was replaced further up, such as by an Option becoming None, can't be resolved
+  /// and is an error. The events are sorted by path.
+  pub fn into_events<'a, T>(&self, base: &T) -> Result<Vec<ChangeEvent>>
+  where
+    T: Patchwork<'a>,
+  {
+    let mut applied = base.snapshot()?;
+    applied
+      .apply(self)
+      .context("The patch must apply to the base to find the old values")?;
+
+    // Keys at the same depth share a diff
+    let mut undo: HashMap<usize, Patch> = HashMap::new();
+    let mut events = Vec::new();
+    for (key, op) in self.iter() {
+      let old = match op {
+        PatchOp::Add(_) => None,
+        _ => {
+          let depth = match &key[..] {
+            "&self" => 0,
+            _ => key.split('.').count(),
+          };
+          let undo = match undo.entry(depth) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+              entry.insert(applied.diff_with_options(base, &DiffOptions { max_depth: depth })?)
+            }
+          };
+          match undo.get_op(None, key) {
+            Some(undone) => undone.value().cloned(),
+            None if undo.iter().any(|(other, _)| is_ancestor(other, key)) => {
+              return Err(ProteanError::MissingValue(key.clone()))
+                .context("The old value was replaced along with one of its parents");
+            }
+            // Nothing changed at the key, so it's the same as the new value
+            None => op.value().cloned(),
+          }
+        }
+      };
+      events.push(ChangeEvent {
+        path: key.clone(),
+        old,
+        new: op.value().cloned(),
+      });
+    }
+    events.sort_by(|left, right| left.path.cmp(&right.path));
+    Ok(events)
+  }
+
+  /// Build the patch that undoes this one
+  ///
+  /// A patch only stores the new values, so even a patch of plain sets needs the original it was made
+  /// against to find the old ones. Applying the result to `base` patched by self gives back `base`.
+  pub fn reverse<'a, T>(&self, base: &T) -> Result<Patch>
+  where
+    T: Patchwork<'a>,
+  {
+    let mut applied = base.snapshot()?;
+    applied
+      .apply(self)
+      .context("The patch must apply to the base to be reversed")?;
+    applied.diff(base)
+  }
+
+  /// Drop every entry that wouldn't change the base
+  ///
+  /// The whole patch is applied to a snapshot of the base, since entries such as consecutive Adds to a
+  /// vec depend on each other. An entry is kept when its key, or anything above or below it, ended up
+  /// with a different value. A patch that fails to apply is an error rather than being dropped.
+  pub fn normalize<'a, T>(&self, base: &T) -> Result<Patch>
+  where
+    T: Patchwork<'a>,
+  {
+    let changed = base
+      .snapshot()?
+      .apply_changes(self)
+      .context("The patch must apply to the base to be normalized")?;
+    let mut normalized = self.empty_like();
+    for (key, op) in self.iter() {
+      let touched = changed
+        .iter()
+        .any(|path| path == key || is_ancestor(key, path) || is_ancestor(path, key));
+      if touched {
+        normalized.value_map.insert(key.clone(), op.clone());
+        if let Some(version) = self.versions.get(key) {
+          normalized.versions.insert(key.clone(), *version);
+        }
+      }
+    }
+    Ok(normalized)
+  }
+
+  /// A patch with the same type, key and validator but no changes
+  fn empty_like(&self) -> Patch {
+    Patch {
+      patch_type: self.patch_type.clone(),
+      key: self.key,
+      validator: self.validator.clone(),
+      value_map: ValueMap::new(),
+      versions: HashMap::new(),
+      strict_keys: self.strict_keys,
+      #[cfg(feature = "async")]
+      async_validator: self.async_validator.clone(),
+    }
+  }
+
+  /// Move a patch made against one version of a struct onto a newer version of it
+  ///
+  /// Keys where the newer version already holds the patched value are dropped, leaving a minimal patch of
+  /// the changes that still mean something. If the newer version changed a value the patch also changes, a
+  /// RebaseConflict listing those keys is returned instead.
+  pub fn rebase<'a, T>(&self, base_v1: &T, base_v2: &T) -> Result<Patch>
+  where
+    T: Patchwork<'a>,
+  {
+    let drift = base_v1.diff(base_v2)?;
+    let conflicts = self.conflicts_with(&drift);
+    if !conflicts.is_empty() {
+      return Err(ProteanError::RebaseConflict(conflicts))
+        .context("The base changed underneath the patch");
+    }
+
+    let mut target = base_v2.snapshot()?;
+    target.apply(self)?;
+    Ok(Patch {
+      value_map: base_v2.diff(&target)?.value_map,
+      ..self.clone()
+    })
+  }
+
+  // --------  Static helpers
+
+  /// Place a single key under the prefix, with `&self` becoming the prefix itself
+  pub fn prefix_key(prefix: &str, key: &str) -> String {
+    match key {
+      "&self" => prefix.to_string(),
+      _ => format!("{}.{}", prefix, key),
+    }
+  }
+
+  /// Convert a patch to its original type
+  ///
+  /// This assumes there is enough data in the patch for all the non-optional values. Essentially,
+  /// this is a serialized form with all the unset optional fields removed.
+  /// THINK:
+  /// - Add coerce option which ignores the type?
+  pub fn from_patch<'a, T>(prefix: Option<String>, patch: &Patch) -> Result<T>
+  where
+    T: Patchwork<'a>,
+  {
+    T::from_patch(prefix, &patch).context("Could not create a Test Object from patch")
+  }
+
+  /// Deserialize a value stored in a patch into the type it is being applied to
+  ///
+  /// The key is only used to give the error some context
+  pub fn deserialize_value<'a, T>(key: &str, value: &serde_json::Value) -> Result<T>
+  where
+    T: Deserialize<'a>,
+  {
+    T::deserialize(value.clone())
+      .map_err(|err| ProteanError::DeserializeFailed(key.to_string(), err.to_string()).into())
+  }
+
+  /// Merge the changes made to two copies of a common ancestor
+  ///
+  /// Both copies are diffed against the base, and every change that does not conflict is applied to a
+  /// clone of it. Conflicting keys are left at their base value and returned alongside the merged
+  /// struct so the caller can decide how to reconcile them.
+  pub fn three_way_merge<'a, T>(base: &T, left: &T, right: &T) -> Result<(T, Vec<String>)>
+  where
+    T: Patchwork<'a>,
+  {
+    let ours = base.diff(left)?;
+    let theirs = base.diff(right)?;
+    let conflicts = ours.conflicts_with(&theirs);
+
+    let mut merged = base.new_patch();
+    for patch in &[ours, theirs] {
+      for (key, op) in patch.iter() {
+        let conflicted = conflicts
+          .iter()
+          .any(|conflict| conflict == key || is_ancestor(conflict, key));
+        if !conflicted {
+          merged.put(key.clone(), op.clone())?;
+        }
+      }
+    }
+
+    let mut result = base.snapshot()?;
+    result
+      .apply(&merged)
+      .context("Could not apply the merged patch to the base")?;
+    Ok((result, conflicts))
+  }
+}
+
+/// Check if the key located at the path `parent` contains the value at `child`
+fn is_ancestor(parent: &str, child: &str) -> bool {
+  match parent {
+    "&self" => child != "&self",
+    _ => child
+      .strip_prefix(parent)
+      .is_some_and(|rest| rest.starts_with('.')),
+  }
+}
+
+/// The length of a string once it is quoted and escaped as JSON
+///
+/// Control characters are counted as a full \u0000 escape, even though some have a shorter form
+fn json_string_size(value: &str) -> usize {
+  value.chars().fold(2, |size, c| {
+    size
+      + match c {
+        '"' | '\\' => 2,
+        c if (c as u32) < 0x20 => 6,
+        c => c.len_utf8(),
+      }
+  })
+}
+
+/// Order keys by segment, comparing indices as numbers so "10" comes after "9"
+///
+/// Parents always sort ahead of their children, and "&self" ahead of everything.
+fn compare_keys(left: &str, right: &str) -> std::cmp::Ordering {
+  match (left == "&self", right == "&self") {
+    (true, true) => return std::cmp::Ordering::Equal,
+    (true, false) => return std::cmp::Ordering::Less,
+    (false, true) => return std::cmp::Ordering::Greater,
+    _ => (),
+  }
+  let segment = |segment: &str| (segment.parse::<usize>().ok(), segment.to_string());
+  left
+    .split('.')
+    .map(segment)
+    .cmp(right.split('.').map(segment))
+}
+
+/// Turn a patch key into a JSON Pointer, escaping each segment as RFC 6901 requires
+fn json_pointer(key: &str) -> String {
+  match key {
+    "&self" => String::new(),
+    _ => key
+      .split('.')
+      .map(|segment| format!("/{}", segment.replace('~', "~0").replace('/', "~1")))
+      .collect(),
+  }
+}
+
+/// Split a key into the path of its parent and its last segment, using "&self" for the root
+fn split_key(key: &str) -> (&str, &str) {
+  match key.rfind('.') {
+    Some(position) => (&key[..position], &key[position + 1..]),
+    None => ("&self", key),
+  }
+}
+
+/// The reverse of split_key, adding a segment onto the end of a parent key
+fn join_key(parent: &str, last: &str) -> String {
+  match parent {
+    "&self" => last.to_string(),
+    _ => format!("{}.{}", parent, last),
+  }
+}
+
+/// Make sure a map key can be used as one segment of a patch key
+fn check_segment(segment: &str, context: &str) -> Result<()> {
+  match segment.contains('.') {
+    true => Err(ProteanError::MalformedPatch(format!(
+      "the key '{}' contains a '.'",
+      segment
+    )))
+    .context(context.to_string()),
+    false => Ok(()),
+  }
+}
+
+/// Read a segment of a key as an array index
+fn json_index(key: &str, segment: &str) -> Result<usize> {
+  segment
+    .parse::<usize>()
+    .map_err(|_| ProteanError::UnknownKey(key.to_string()))
+    .context("Array keys must be the index of an element")
+}
+
+/// Walk down to the node at the path, optionally creating objects for anything missing on the way
+///
+/// Without create, a missing node is returned as None rather than an error.
+fn json_node_mut<'v>(
+  value: &'v mut serde_json::Value,
+  path: &str,
+  create: bool,
+) -> Result<Option<&'v mut serde_json::Value>> {
+  use serde_json::Value;
+
+  if path == "&self" {
+    return Ok(Some(value));
+  }
+  let mut node = value;
+  for segment in path.split('.') {
+    if create && !node.is_object() && !node.is_array() {
+      *node = Value::Object(serde_json::Map::new());
+    }
+    node = match node {
+      Value::Object(fields) => match create {
+        true => fields.entry(segment.to_string()).or_insert(Value::Null),
+        false => match fields.get_mut(segment) {
+          Some(child) => child,
+          None => return Ok(None),
+        },
+      },
+      Value::Array(items) => match items.get_mut(json_index(path, segment)?) {
+        Some(child) => child,
+        None => return Ok(None),
+      },
+      _ => return Ok(None),
+    };
+  }
+  Ok(Some(node))
+}
+
+/// Collect the paths where two serialized values differ
+///
+/// Objects and arrays are walked so only the values that changed are listed, rather than their parents
+fn changed_paths(
+  path: &str,
+  before: &serde_json::Value,
+  after: &serde_json::Value,
+  changed: &mut Vec<String>,
+) {
+  use serde_json::Value;
+
+  if before == after {
+    return;
+  }
+  let join = |key: &str| match path {
+    "&self" => key.to_string(),
+    _ => Patch::prefix_key(path, key),
+  };
+  match (before, after) {
+    (Value::Object(left), Value::Object(right)) => {
+      let keys: BTreeSet<&String> = left.keys().chain(right.keys()).collect();
+      for key in keys {
+        let child = join(key);
+        match (left.get(key), right.get(key)) {
+          (Some(left), Some(right)) => changed_paths(&child, left, right, changed),
+          _ => changed.push(child),
+        }
+      }
+    }
+    (Value::Array(left), Value::Array(right)) => {
+      for index in 0..left.len().max(right.len()) {
+        let child = join(&index.to_string());
+        match (left.get(index), right.get(index)) {
+          (Some(left), Some(right)) => changed_paths(&child, left, right, changed),
+          _ => changed.push(child),
+        }
+      }
+    }
+    _ => changed.push(path.to_string()),
+  }
+}
+
+/// Apply a single change to a value that a patch is adding or setting
+///
+/// BTreeSet changes are merged into the array the set was serialized as, and everything else is applied
+/// the same way as apply_to_json.
+fn fold_json(value: &mut serde_json::Value, key: &str, op: &PatchOp) -> Result<()> {
+  let (parent, last) = split_key(key);
+  if let PatchOp::Add(serde_json::Value::Array(items)) = op {
+    if let Some(serde_json::Value::Array(set)) = json_node_mut(value, parent, false)? {
+      match last {
+        "added" => {
+          for item in items {
+            if !set.contains(item) {
+              set.push(item.clone());
+            }
+          }
+          return Ok(());
+        }
+        "removed" => {
+          set.retain(|item| !items.contains(item));
+          return Ok(());
+        }
+        _ => (),
+      }
+    }
+  }
+  let mut single = Patch::new("");
+  single.value_map.insert(key.to_string(), op.clone());
+  single.apply_to_json(value)
+}
+
+/// The length of a change once it is serialized as compact JSON
+fn json_op_size(op: &PatchOp) -> usize {
+  match op {
+    // {"Add":value} and {"Set":value}
+    PatchOp::Add(value) | PatchOp::Set(value) => 8 + json_value_size(value),
+    // "Remove"
+    PatchOp::Remove => 8,
+  }
+}
+
+/// The length of a value once it is serialized as compact JSON
+fn json_value_size(value: &serde_json::Value) -> usize {
+  use serde_json::Value;
+  match value {
+    Value::Null => 4,
+    Value::Bool(true) => 4,
+    Value::Bool(false) => 5,
+    // Numbers are small, and formatting them is the only way to be sure of the length
+    Value::Number(_) => value.to_string().len(),
+    Value::String(string) => json_string_size(string),
+    Value::Array(items) => items
+      .iter()
+      .fold(2, |size, item| size + json_value_size(item) + 1),
+    Value::Object(map) => map.iter().fold(2, |size, (key, item)| {
+      size + json_string_size(key) + json_value_size(item) + 2
+    }),
+  }
+}
+
+//****************************************   Primitive Type Implementations ********************************/
+/// Implement all the primitives with a common set of code.
+///
+/// These are types of values that simple equality works for. String is included, as we are looking at it
+/// holistically and not as an array of characters. Types where == isn't strict enough can pass their own
+/// comparison as a second argument.
+///
+/// ```
+/// use protean::Patchwork;
+/// let patch = 10i32.diff(&11).unwrap();
+/// assert_eq!(patch.get(None, "&self"), Some(&serde_json::json!(11)));
+/// ```
+macro_rules! primitive_patchwork {
+  ($type:ty) => {
+    primitive_patchwork! {$type, |left: &$type, right: &$type| left == right}
+  };
+  ($type:ty, $same:expr) => {
+    impl<'a> Patchwork<'a> for $type {
+      fn diff(&self, struct2: &$type) -> Result<Patch> {
+        let mut patch = self.new_patch();
+        let same: fn(&$type, &$type) -> bool = $same;
+        if !same(self, struct2) {
+          patch.add(&"&self".to_string(), &serde_json::to_value(struct2)?)?;
+        }
+        Ok(patch)
+      }
+
+      fn apply(&mut self, patch: &Patch) -> Result<()> {
+        for (key, op) in patch.iter() {
+          match (&key[..], op.value()) {
+            ("&self", Some(value)) => *self = Patch::deserialize_value(key, value)?,
+            ("&self", None) => Err(ProteanError::CannotRemove(key.clone())).context(format!(
+              "Removal needs to be handled by the container holding the '{}'",
+              stringify!($type)
+            ))?,
+            _ => Err(ProteanError::UnknownKey(key.clone())).context(format!(
+              "Cannot apply a nested key to '{}'",
+              stringify!($type)
+            ))?,
+          }
+        }
+        Ok(())
+      }
+
+      fn to_patch(&self) -> Result<Patch> {
+        Ok(
+          self
+            .new_patch()
+            .add(&"&self".to_string(), &serde_json::to_value(self)?)?,
+        )
+      }
+    }
+  };
+}
+
+// Basic Primitives
+primitive_patchwork! {bool}
+
+primitive_patchwork! {i8}
+primitive_patchwork! {i16}
+primitive_patchwork! {i32}
+primitive_patchwork! {i64}
+primitive_patchwork! {i128}
+primitive_patchwork! {isize}
+
+primitive_patchwork! {u8}
+primitive_patchwork! {u16}
+primitive_patchwork! {u32}
+primitive_patchwork! {u64}
+primitive_patchwork! {u128}
+primitive_patchwork! {usize}
+
+primitive_patchwork! {f32}
+primitive_patchwork! {f64}
+
+// Serde rejects a zero when deserializing these, so a patch setting one fails with DeserializeFailed
+primitive_patchwork! {std::num::NonZeroI8}
+primitive_patchwork! {std::num::NonZeroI16}
+primitive_patchwork! {std::num::NonZeroI32}
+primitive_patchwork! {std::num::NonZeroI64}
+primitive_patchwork! {std::num::NonZeroI128}
+primitive_patchwork! {std::num::NonZeroIsize}
+
+primitive_patchwork! {std::num::NonZeroU8}
+primitive_patchwork! {std::num::NonZeroU16}
+primitive_patchwork! {std::num::NonZeroU32}
+primitive_patchwork! {std::num::NonZeroU64}
+primitive_patchwork! {std::num::NonZeroU128}
+primitive_patchwork! {std::num::NonZeroUsize}
+
+primitive_patchwork! {char}
+primitive_patchwork! {String}
+
+// Serde writes Duration as seconds and nanoseconds, and SystemTime as the Duration since UNIX_EPOCH. That
+// means times before the epoch can't be serialized, so diffing to one is an error rather than a patch.
+primitive_patchwork! {std::time::Duration}
+primitive_patchwork! {std::time::SystemTime}
+
+primitive_patchwork! {std::net::IpAddr}
+primitive_patchwork! {std::net::Ipv4Addr}
+primitive_patchwork! {std::net::Ipv6Addr}
+primitive_patchwork! {std::net::SocketAddr}
+primitive_patchwork! {std::net::SocketAddrV4}
+primitive_patchwork! {std::net::SocketAddrV6}
+
+// Path equality compares components, so "a/b" and "a//b" would be the same. Comparing the OS string
+// keeps the exact path, separators and all.
+primitive_patchwork! {std::path::PathBuf, |left: &std::path::PathBuf, right: &std::path::PathBuf| {
+  left.as_os_str() == right.as_os_str()
+}}
+
+// TODO: &str
+
+// Chrono date/time types are treated as primitives, compared by equality and patched using their serde
+// representation. Serde only supports deserializing DateTime with these three timezones, and
+// chrono::Duration has no serde support so it cannot be made Patchwork
+#[cfg(feature = "chrono")]
+primitive_patchwork! {chrono::DateTime<chrono::Utc>}
+#[cfg(feature = "chrono")]
+primitive_patchwork! {chrono::DateTime<chrono::Local>}
+#[cfg(feature = "chrono")]
+primitive_patchwork! {chrono::DateTime<chrono::FixedOffset>}
+#[cfg(feature = "chrono")]
+primitive_patchwork! {chrono::NaiveDate}
+#[cfg(feature = "chrono")]
+primitive_patchwork! {chrono::NaiveTime}
+#[cfg(feature = "chrono")]
+primitive_patchwork! {chrono::NaiveDateTime}
+
+// Identifiers are only ever replaced whole, so they patch like any other primitive
+#[cfg(feature = "uuid")]
+primitive_patchwork! {uuid::Uuid}
+#[cfg(feature = "url")]
+primitive_patchwork! {url::Url}
+
+/// Untyped JSON is diffed by its structure, the same way the typed impls would diff it
+///
+/// Objects are compared by key, arrays by position like Vec, and anything else by equality. A value that
+/// changes kind is replaced whole. Object keys are used as path segments, so they must not contain a '.'.
+/// Applying is done by Patch::apply_to_json.
+impl<'a> Patchwork<'a> for serde_json::Value {
+  fn diff(&self, struct2: &serde_json::Value) -> Result<Patch> {
+    self.diff_with_options(struct2, &DiffOptions::default())
+  }
+
+  fn diff_with_options(&self, struct2: &serde_json::Value, options: &DiffOptions) -> Result<Patch> {
+    use serde_json::Value;
+
+    let mut patch = self.new_patch();
+    match (self, struct2) {
+      (Value::Object(left), Value::Object(right)) if options.max_depth > 0 => {
+        for key in left.keys().chain(right.keys()) {
+          check_segment(key, "JSON object keys are used as key segments")?;
+        }
+        let options = options.descend();
+        for (key, right) in right.iter() {
+          match left.get(key) {
+            Some(left) => {
+              patch.merge_field(key, left.diff_with_options(right, &options)?)?;
+            }
+            None => {
+              patch.insert(key, right)?;
+            }
+          }
+        }
+        for key in left.keys() {
+          if !right.contains_key(key) {
+            patch.remove(key)?;
+          }
+        }
+        Ok(patch)
+      }
+      (Value::Array(left), Value::Array(right)) => diff_elements(patch, left, right, options),
+      _ => Patch::diff_whole(patch, self, struct2),
+    }
+  }
+
+  fn apply(&mut self, patch: &Patch) -> Result<()> {
+    patch.apply_to_json(self)
+  }
+}
+
+/// Unit has no data, so there is never anything to diff or apply
+impl<'a> Patchwork<'a> for () {
+  fn diff(&self, _struct2: &()) -> Result<Patch> {
+    Ok(self.new_patch())
+  }
+
+  fn apply(&mut self, _patch: &Patch) -> Result<()> {
+    Ok(())
+  }
+}
+
+/// Markers are zero sized like unit, so T doesn't need to be Patchwork
+impl<'a, T: ?Sized> Patchwork<'a> for std::marker::PhantomData<T> {
+  fn diff(&self, _struct2: &std::marker::PhantomData<T>) -> Result<Patch> {
+    Ok(self.new_patch())
+  }
+
+  fn apply(&mut self, _patch: &Patch) -> Result<()> {
+    Ok(())
+  }
+}
+
+//****************************************   Complex Type Implementations ********************************/
+// Complex primitives
+// TODO: HashMap
+
+/// References diff through to the value they point at
+///
+/// A shared reference can't be changed, so applying anything other than an empty patch is an error.
+/// References also can't be deserialized, so containers of them can't be Patchwork.
+impl<'a, 'r, T> Patchwork<'a> for &'r T
+where
+  T: Patchwork<'a>,
+{
+  fn new_patch(&self) -> Patch {
+    (**self).new_patch()
+  }
+
+  fn field_keys(&self) -> Vec<String> {
+    (**self).field_keys()
+  }
+
+  fn diff(&self, struct2: &&'r T) -> Result<Patch> {
+    (**self).diff(*struct2)
+  }
+
+  fn diff_with_options(&self, struct2: &&'r T, options: &DiffOptions) -> Result<Patch> {
+    (**self).diff_with_options(*struct2, options)
+  }
+
+  fn diff_visit(
+    &self,
+    struct2: &&'r T,
+    visitor: &mut dyn FnMut(&str, &PatchOp) -> Result<()>,
+  ) -> Result<()> {
+    (**self).diff_visit(*struct2, visitor)
+  }
+
+  fn apply(&mut self, patch: &Patch) -> Result<()> {
+    match patch.is_empty() {
+      true => Ok(()),
+      false => Err(ProteanError::ImmutableReference)
+        .context(format!("Cannot apply to a &{}", std::any::type_name::<T>())),
+    }
+  }
+}
+
+/// Options are transparent, matching how serde treats them
+///
+/// Two Somes are diffed by their contents with no extra key, and going to or from None replaces the
+/// whole value.
+impl<'a, T> Patchwork<'a> for Option<T>
+where
+  T: Patchwork<'a> + Deserialize<'a>,
+{
+  fn diff(&self, struct2: &Option<T>) -> Result<Patch> {
+    self.diff_with_options(struct2, &DiffOptions::default())
+  }
+
+  // Like Cow, the Option isn't a level of its own so the options go straight to the contents
+  fn diff_with_options(&self, struct2: &Option<T>, options: &DiffOptions) -> Result<Patch> {
+    match (self, struct2) {
+      (Some(left), Some(right)) => left.diff_with_options(right, options),
+      (None, None) => Ok(self.new_patch()),
+      _ => {
+        let mut patch = self.new_patch();
+        patch.add(&"&self".to_string(), &serde_json::to_value(struct2)?)?;
+        Ok(patch)
+      }
+    }
+  }
+
+  fn snapshot(&self) -> Result<Option<T>> {
+    match self {
+      Some(inner) => Ok(Some(inner.snapshot()?)),
+      None => Ok(None),
+    }
+  }
+
+  fn restore(&mut self, snapshot: &mut Option<T>) {
+    match (self, snapshot) {
+      (Some(inner), Some(saved)) => inner.restore(saved),
+      (current, saved) => std::mem::swap(current, saved),
+    }
+  }
+
+  fn apply(&mut self, patch: &Patch) -> Result<()> {
+    if let Some(value) = patch.get(None, "&self") {
+      *self = Patch::deserialize_value("&self", value)?;
+    }
+    if patch.child_keys().is_empty() {
+      return Ok(());
+    }
+    match self {
+      Some(inner) => inner.apply(patch),
+      None => Err(ProteanError::MissingValue(patch.child_keys().remove(0)))
+        .context("Cannot patch inside of a None"),
+    }
+  }
+}
 
-      fn to_patch(&self) -> Result<Patch> {
-        Ok(
-          self
-            .new_patch()
-            .add(&"&self".to_string(), &serde_json::to_value(self)?)?,
-        )
+/// Diff two lists by position, with the index used as the key
+///
+/// Elements sharing an index are diffed against each other. When the lists are different lengths, the
+/// extra elements in the right are added and those missing from the left are removed.
+fn diff_elements<'a, T>(
+  mut patch: Patch,
+  left: &[T],
+  right: &[T],
+  options: &DiffOptions,
+) -> Result<Patch>
+where
+  T: Patchwork<'a>,
+{
+  if options.max_depth == 0 {
+    return Patch::diff_whole(patch, left, right);
+  }
+  let options = options.descend();
+  for (index, (left, right)) in left.iter().zip(right.iter()).enumerate() {
+    patch.merge_field(&index.to_string(), left.diff_with_options(right, &options)?)?;
+  }
+  for (index, added) in right.iter().enumerate().skip(left.len()) {
+    patch.insert(&index.to_string(), &serde_json::to_value(added)?)?;
+  }
+  for index in right.len()..left.len() {
+    patch.remove(&index.to_string())?;
+  }
+  Ok(patch)
+}
+
+/// The diff_visit version of diff_elements
+fn visit_elements<'a, T>(
+  left: &[T],
+  right: &[T],
+  visitor: &mut dyn FnMut(&str, &PatchOp) -> Result<()>,
+) -> Result<()>
+where
+  T: Patchwork<'a>,
+{
+  for (index, (left, right)) in left.iter().zip(right.iter()).enumerate() {
+    let prefix = index.to_string();
+    left.diff_visit(right, &mut |key: &str, op: &PatchOp| {
+      visitor(&Patch::prefix_key(&prefix, key), op)
+    })?;
+  }
+  for (index, added) in right.iter().enumerate().skip(left.len()) {
+    visitor(
+      &index.to_string(),
+      &PatchOp::Add(serde_json::to_value(added)?),
+    )?;
+  }
+  for index in right.len()..left.len() {
+    visitor(&index.to_string(), &PatchOp::Remove)?;
+  }
+  Ok(())
+}
+
+/// Changes to the length of a list that apply_elements can't make itself
+#[derive(Default)]
+struct Resize {
+  /// Indices of the elements to take out of the list
+  removed: Vec<usize>,
+
+  /// Values to append, along with the index they are expected at
+  added: Vec<(usize, serde_json::Value)>,
+}
+
+/// Apply the index keys of a patch to the matching elements of a list
+///
+/// Slices can't change length, so additions and removals are handed back for the caller to deal with
+fn apply_elements<'a, T>(items: &mut [T], patch: &Patch) -> Result<Resize>
+where
+  T: Patchwork<'a>,
+{
+  let mut resize = Resize::default();
+  for key in patch.child_keys() {
+    let index = key
+      .parse::<usize>()
+      .map_err(|_| ProteanError::UnknownKey(key.clone()))
+      .context("List keys must be the index of an element")?;
+
+    match patch.get_op(None, &key) {
+      Some(PatchOp::Remove) => resize.removed.push(index),
+      Some(PatchOp::Add(value)) => resize.added.push((index, value.clone())),
+      _ => {
+        let element = items
+          .get_mut(index)
+          .ok_or_else(|| ProteanError::MissingValue(key.clone()))
+          .context("List keys must be the index of an existing element")?;
+        element.apply(&patch.strip_prefix(&key))?;
+      }
+    }
+  }
+  Ok(resize)
+}
+
+/// Restore a list element by element, so any shared state inside of them is kept
+///
+/// Lists of different lengths can't be matched up, so the snapshot is swapped in instead.
+fn restore_elements<'a, T, L>(items: &mut L, snapshot: &mut L)
+where
+  T: Patchwork<'a>,
+  L: AsMut<[T]>,
+{
+  let (items_slice, saved_slice) = (items.as_mut(), snapshot.as_mut());
+  if items_slice.len() != saved_slice.len() {
+    return std::mem::swap(items, snapshot);
+  }
+  for (item, saved) in items_slice.iter_mut().zip(saved_slice.iter_mut()) {
+    item.restore(saved);
+  }
+}
+
+/// Vectors are compared by position
+///
+/// Elements at the same index are diffed against each other, with the index used as the key. Growing the
+/// vec adds the new elements at their index and shrinking removes the extras. Use diff_vec_by_key when
+/// elements can be inserted or reordered.
+impl<'a, T> Patchwork<'a> for Vec<T>
+where
+  T: Patchwork<'a> + Deserialize<'a>,
+{
+  fn diff(&self, struct2: &Vec<T>) -> Result<Patch> {
+    self.diff_with_options(struct2, &DiffOptions::default())
+  }
+
+  fn diff_with_options(&self, struct2: &Vec<T>, options: &DiffOptions) -> Result<Patch> {
+    diff_elements(self.new_patch(), self, struct2, options)
+  }
+
+  fn diff_visit(
+    &self,
+    struct2: &Vec<T>,
+    visitor: &mut dyn FnMut(&str, &PatchOp) -> Result<()>,
+  ) -> Result<()> {
+    visit_elements(self, struct2, visitor)
+  }
+
+  fn snapshot(&self) -> Result<Vec<T>> {
+    self.iter().map(Patchwork::snapshot).collect()
+  }
+
+  fn restore(&mut self, snapshot: &mut Vec<T>) {
+    restore_elements(self, snapshot)
+  }
+
+  fn apply(&mut self, patch: &Patch) -> Result<()> {
+    if let Some(value) = patch.get(None, "&self") {
+      *self = Patch::deserialize_value("&self", value)?;
+    }
+    let mut resize = apply_elements(self, patch)?;
+
+    // Remove from the back so the indices stay valid
+    resize
+      .removed
+      .sort_unstable_by(|left, right| right.cmp(left));
+    for index in resize.removed {
+      if index >= self.len() {
+        return Err(ProteanError::MissingValue(index.to_string()))
+          .context("Cannot remove an element past the end of the vec");
+      }
+      self.remove(index);
+    }
+
+    resize.added.sort_by_key(|(index, _)| *index);
+    for (index, value) in resize.added {
+      if index != self.len() {
+        return Err(ProteanError::MissingValue(index.to_string()))
+          .context("Elements can only be added to the end of the vec");
+      }
+      self.push(Patch::deserialize_value(&index.to_string(), &value)?);
+    }
+    Ok(())
+  }
+}
+
+/// Patchwork for a sequence that isn't stored contiguously, by way of a Vec
+///
+/// The elements are collected into a Vec to reuse its positional diff and apply, so the keys and length
+/// handling are exactly the same as for a Vec.
+macro_rules! sequence_patchwork {
+  ($type:ident) => {
+    impl<'a, T> Patchwork<'a> for std::collections::$type<T>
+    where
+      T: Patchwork<'a> + Deserialize<'a>,
+    {
+      fn diff(&self, struct2: &std::collections::$type<T>) -> Result<Patch> {
+        self.diff_with_options(struct2, &DiffOptions::default())
+      }
+
+      fn diff_with_options(
+        &self,
+        struct2: &std::collections::$type<T>,
+        options: &DiffOptions,
+      ) -> Result<Patch> {
+        let left: Vec<T> = self.iter().cloned().collect();
+        let right: Vec<T> = struct2.iter().cloned().collect();
+        diff_elements(self.new_patch(), &left, &right, options)
+      }
+
+      fn diff_visit(
+        &self,
+        struct2: &std::collections::$type<T>,
+        visitor: &mut dyn FnMut(&str, &PatchOp) -> Result<()>,
+      ) -> Result<()> {
+        let left: Vec<T> = self.iter().cloned().collect();
+        let right: Vec<T> = struct2.iter().cloned().collect();
+        visit_elements(&left, &right, visitor)
+      }
+
+      fn snapshot(&self) -> Result<std::collections::$type<T>> {
+        self.iter().map(Patchwork::snapshot).collect()
+      }
+
+      fn restore(&mut self, snapshot: &mut std::collections::$type<T>) {
+        match self.len() == snapshot.len() {
+          true => {
+            for (item, saved) in self.iter_mut().zip(snapshot.iter_mut()) {
+              item.restore(saved);
+            }
+          }
+          false => std::mem::swap(self, snapshot),
+        }
+      }
+
+      fn apply(&mut self, patch: &Patch) -> Result<()> {
+        let mut items: Vec<T> = std::mem::take(self).into_iter().collect();
+        let result = items.apply(patch);
+        *self = items.into_iter().collect();
+        result
       }
     }
   };
 }
 
-// Basic Primitives
-primitive_patchwork! {bool}
+// Deques are compared by position from the front, the same as a Vec. Pushing onto the front moves every
+// element to a new index, so it shows up as a change to each of them plus an addition at the end.
+sequence_patchwork!(VecDeque);
 
-primitive_patchwork! {i8}
-primitive_patchwork! {i16}
-primitive_patchwork! {i32}
-primitive_patchwork! {i64}
-primitive_patchwork! {i128}
-primitive_patchwork! {isize}
+// Linked lists are compared by position, the same as a Vec
+sequence_patchwork!(LinkedList);
 
-primitive_patchwork! {u8}
-primitive_patchwork! {u16}
-primitive_patchwork! {u32}
-primitive_patchwork! {u64}
-primitive_patchwork! {u128}
-primitive_patchwork! {usize}
+/// Fixed size arrays are compared by position
+///
+/// This mirrors Vec, but since the length can't change only the elements that differ are ever reported.
+/// Serde can only serialize arrays of up to 32 elements, which limits the sizes that are Patchwork.
+impl<'a, T, const N: usize> Patchwork<'a> for [T; N]
+where
+  T: Patchwork<'a>,
+  [T; N]: Serialize + Deserialize<'a>,
+{
+  fn diff(&self, struct2: &[T; N]) -> Result<Patch> {
+    self.diff_with_options(struct2, &DiffOptions::default())
+  }
 
-primitive_patchwork! {f32}
-primitive_patchwork! {f64}
+  fn diff_with_options(&self, struct2: &[T; N], options: &DiffOptions) -> Result<Patch> {
+    diff_elements(self.new_patch(), self, struct2, options)
+  }
 
-primitive_patchwork! {char}
-primitive_patchwork! {String}
+  fn diff_visit(
+    &self,
+    struct2: &[T; N],
+    visitor: &mut dyn FnMut(&str, &PatchOp) -> Result<()>,
+  ) -> Result<()> {
+    visit_elements(self, struct2, visitor)
+  }
 
-// TODO: &str
+  fn snapshot(&self) -> Result<[T; N]> {
+    let mut copy = self.clone();
+    for (copied, original) in copy.iter_mut().zip(self.iter()) {
+      *copied = original.snapshot()?;
+    }
+    Ok(copy)
+  }
 
-//****************************************   Complex Type Implementations ********************************/
-// Complex primitives
-// TODO: &T
-// TODO: Option
-// TODO: Vec
-// TODO: HashMap
+  fn restore(&mut self, snapshot: &mut [T; N]) {
+    restore_elements(self, snapshot)
+  }
+
+  fn apply(&mut self, patch: &Patch) -> Result<()> {
+    if let Some(value) = patch.get(None, "&self") {
+      *self = Patch::deserialize_value("&self", value)?;
+    }
+    let resize = apply_elements(self, patch)?;
+    if let Some(index) = resize
+      .removed
+      .into_iter()
+      .chain(resize.added.into_iter().map(|(index, _)| index))
+      .next()
+    {
+      return Err(ProteanError::MissingValue(index.to_string()))
+        .context("Arrays are a fixed size, so elements cannot be added or removed");
+    }
+    Ok(())
+  }
+}
+
+/// Diff two lists, matching elements by an identity rather than their position
+///
+/// Each element is identified by the value returned from key_fn, which is also used as its key in the
+/// patch so the changes survive reordering. Matching elements are diffed, new elements are added whole and
+/// removed elements are marked with a removal. Identities are used as key segments, so they can't contain
+/// a '.'.
+///
+/// The keys aren't positions, so the patch has to be applied with apply_vec_by_key and the same key_fn.
+///
+/// ```ignore
+/// let patch = diff_vec_by_key(&before, &after, |user| user.id)?;
+/// apply_vec_by_key(&mut before, &patch, |user| user.id)?;
+/// ```
+pub fn diff_vec_by_key<'a, T, K, F>(left: &[T], right: &[T], key_fn: F) -> Result<Patch>
+where
+  T: Patchwork<'a>,
+  K: std::fmt::Display,
+  F: Fn(&T) -> K,
+{
+  let index = |items: &[T]| -> Result<HashMap<String, usize>> {
+    let mut found = HashMap::new();
+    for (position, item) in items.iter().enumerate() {
+      let key = key_fn(item).to_string();
+      if key.contains('.') {
+        return Err(ProteanError::MalformedPatch(format!(
+          "the identity '{}' contains a '.'",
+          key
+        )))
+        .context("diff_vec_by_key uses identities as key segments");
+      }
+      if found.insert(key.clone(), position).is_some() {
+        return Err(ProteanError::DuplicateKey(key))
+          .context("diff_vec_by_key requires unique keys");
+      }
+    }
+    Ok(found)
+  };
+  let left_index = index(left)?;
+  let right_index = index(right)?;
+
+  let mut patch = Patch::new(std::any::type_name::<Vec<T>>());
+  for (key, position) in right_index.iter() {
+    match left_index.get(key) {
+      Some(original) => {
+        patch.merge_field(key, left[*original].diff(&right[*position])?)?;
+      }
+      None => {
+        patch.insert(key, &serde_json::to_value(&right[*position])?)?;
+      }
+    }
+  }
+  for key in left_index.keys() {
+    if !right_index.contains_key(key) {
+      patch.remove(key)?;
+    }
+  }
+  Ok(patch)
+}
+
+/// Apply a patch made by diff_vec_by_key, finding each element by the same identity
+///
+/// Changed elements are patched in place and removed ones are taken out, while an element that is already
+/// gone is left alone like a BTreeMap entry. The patch doesn't record where new elements went, so they are
+/// added to the end in the order of their keys.
+pub fn apply_vec_by_key<'a, T, K, F>(items: &mut Vec<T>, patch: &Patch, key_fn: F) -> Result<()>
+where
+  T: Patchwork<'a> + Deserialize<'a>,
+  K: std::fmt::Display,
+  F: Fn(&T) -> K,
+{
+  if let Some(value) = patch.get(None, "&self") {
+    *items = Patch::deserialize_value("&self", value)?;
+  }
+
+  let keys: Vec<String> = items.iter().map(|item| key_fn(item).to_string()).collect();
+  let mut removed = Vec::new();
+  let mut added = Vec::new();
+  for key in patch.child_keys() {
+    let position = keys.iter().position(|existing| *existing == key);
+    match (position, patch.get_op(None, &key)) {
+      (Some(position), Some(PatchOp::Remove)) => removed.push(position),
+      (Some(_), Some(PatchOp::Add(_))) => {
+        return Err(ProteanError::DuplicateKey(key))
+          .context("The patch adds an element that is already in the list")
+      }
+      (Some(position), _) => items[position].apply(&patch.strip_prefix(&key))?,
+      (None, Some(PatchOp::Remove)) => (),
+      (None, Some(PatchOp::Add(value))) => added.push(Patch::deserialize_value(&key, value)?),
+      (None, _) => {
+        return Err(ProteanError::MissingValue(key))
+          .context("No element in the list has the identity being patched")
+      }
+    }
+  }
+
+  removed.sort_unstable_by(|left, right| right.cmp(left));
+  for position in removed {
+    items.remove(position);
+  }
+  items.extend(added);
+  Ok(())
+}
+
+/// Results are diffed inside of the variant they hold
+///
+/// When both sides are the same variant the inner values are diffed under an `Ok` or `Err` key, so
+/// `Err(a)` to `Err(b)` only reports what changed in the error. Switching variants replaces the whole
+/// Result.
+impl<'a, T, E> Patchwork<'a> for std::result::Result<T, E>
+where
+  T: Patchwork<'a> + Deserialize<'a>,
+  E: Patchwork<'a> + Deserialize<'a>,
+{
+  fn diff(&self, struct2: &std::result::Result<T, E>) -> Result<Patch> {
+    self.diff_with_options(struct2, &DiffOptions::default())
+  }
+
+  fn diff_with_options(
+    &self,
+    struct2: &std::result::Result<T, E>,
+    options: &DiffOptions,
+  ) -> Result<Patch> {
+    let mut patch = self.new_patch();
+    if options.max_depth == 0 {
+      return Patch::diff_whole(patch, self, struct2);
+    }
+    let options = options.descend();
+    match (self, struct2) {
+      (Ok(left), Ok(right)) => {
+        patch.merge_field("Ok", left.diff_with_options(right, &options)?)?;
+      }
+      (Err(left), Err(right)) => {
+        patch.merge_field("Err", left.diff_with_options(right, &options)?)?;
+      }
+      _ => {
+        patch.add(&"&self".to_string(), &serde_json::to_value(struct2)?)?;
+      }
+    }
+    Ok(patch)
+  }
+
+  fn snapshot(&self) -> Result<std::result::Result<T, E>> {
+    match self {
+      Ok(value) => Ok(Ok(value.snapshot()?)),
+      Err(err) => Ok(Err(err.snapshot()?)),
+    }
+  }
+
+  fn restore(&mut self, snapshot: &mut std::result::Result<T, E>) {
+    match (self, snapshot) {
+      (Ok(value), Ok(saved)) => value.restore(saved),
+      (Err(err), Err(saved)) => err.restore(saved),
+      (current, saved) => std::mem::swap(current, saved),
+    }
+  }
+
+  fn apply(&mut self, patch: &Patch) -> Result<()> {
+    if let Some(value) = patch.get(None, "&self") {
+      *self = Patch::deserialize_value("&self", value)?;
+    }
+    for child in patch.child_keys() {
+      let sub_patch = patch.strip_prefix(&child);
+      match (&child[..], &mut *self) {
+        ("Ok", Ok(inner)) => inner.apply(&sub_patch)?,
+        ("Err", Err(inner)) => inner.apply(&sub_patch)?,
+        ("Ok", _) | ("Err", _) => Err(ProteanError::MissingValue(child.clone()))
+          .context("Result keys must match the variant currently held")?,
+        _ => Err(ProteanError::UnknownKey(child.clone()))
+          .context("Result keys must be 'Ok' or 'Err'")?,
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Copy on write values are compared by the data they hold
+///
+/// Borrowed and owned values holding equal data are the same as far as the diff is concerned. Applying a
+/// patch takes ownership of the data, since it has to be mutated.
+impl<'a, 'b, T> Patchwork<'a> for Cow<'b, T>
+where
+  T: ToOwned + ?Sized,
+  T::Owned: Patchwork<'a>,
+  Cow<'b, T>: Debug + Serialize + Deserialize<'a>,
+{
+  fn diff(&self, struct2: &Cow<'b, T>) -> Result<Patch> {
+    self.diff_with_options(struct2, &DiffOptions::default())
+  }
+
+  // The Cow itself isn't a level, so the options are handed straight to the data it holds
+  fn diff_with_options(&self, struct2: &Cow<'b, T>, options: &DiffOptions) -> Result<Patch> {
+    match (self, struct2) {
+      (Cow::Owned(left), Cow::Owned(right)) => left.diff_with_options(right, options),
+      // Borrowed data can only be compared as the owned type, so it needs to be copied first
+      _ => {
+        let (left, right): (T::Owned, T::Owned) = ((**self).to_owned(), (**struct2).to_owned());
+        left.diff_with_options(&right, options)
+      }
+    }
+  }
+
+  fn apply(&mut self, patch: &Patch) -> Result<()> {
+    match patch.is_empty() {
+      true => Ok(()),
+      false => self.to_mut().apply(patch),
+    }
+  }
+}
+
+/// Shared values are compared by the data they point at
+///
+/// Applying a patch goes through Arc::make_mut, so a value still shared with other Arcs is copied first
+/// and the others keep seeing the old data.
+impl<'a, T> Patchwork<'a> for Arc<T>
+where
+  T: Patchwork<'a>,
+  Arc<T>: Serialize,
+{
+  fn new_patch(&self) -> Patch {
+    (**self).new_patch()
+  }
+
+  fn field_keys(&self) -> Vec<String> {
+    (**self).field_keys()
+  }
+
+  fn diff(&self, struct2: &Arc<T>) -> Result<Patch> {
+    (**self).diff(struct2)
+  }
+
+  // Like Cow, the Arc isn't a level of its own so the options go straight to the data
+  fn diff_with_options(&self, struct2: &Arc<T>, options: &DiffOptions) -> Result<Patch> {
+    (**self).diff_with_options(struct2, options)
+  }
+
+  // The data may hold shared state of its own, so sharing it with the snapshot isn't safe
+  fn snapshot(&self) -> Result<Arc<T>> {
+    Ok(Arc::new((**self).snapshot()?))
+  }
+
+  fn apply(&mut self, patch: &Patch) -> Result<()> {
+    match patch.is_empty() {
+      true => Ok(()),
+      false => Arc::make_mut(self).apply(patch),
+    }
+  }
+}
+
+/// Shared state behind a lock is diffed and patched in place while holding it
+///
+/// A Mutex isn't Clone, so it can only be Patchwork from inside of an Arc. Unlike a plain Arc, applying
+/// a patch changes the value every holder sees. A lock poisoned by a panic is a PoisonedLock error.
+///
+/// Cloning the Arc still shares the lock, so snapshot copies the guarded value into a new one and restore
+/// writes back through the existing lock. Only one lock is ever held at a time, so diffing two of them
+/// from different threads in opposite orders can't deadlock.
+impl<'a, T> Patchwork<'a> for Arc<Mutex<T>>
+where
+  T: Patchwork<'a>,
+  Arc<Mutex<T>>: Serialize,
+{
+  fn new_patch(&self) -> Patch {
+    match self.lock() {
+      Ok(guard) => guard.new_patch(),
+      // The patch type doesn't depend on the data, so a poisoned copy can still name it
+      Err(poisoned) => poisoned.into_inner().new_patch(),
+    }
+  }
+
+  fn diff(&self, struct2: &Arc<Mutex<T>>) -> Result<Patch> {
+    self.diff_with_options(struct2, &DiffOptions::default())
+  }
+
+  fn diff_with_options(&self, struct2: &Arc<Mutex<T>>, options: &DiffOptions) -> Result<Patch> {
+    // Both sides are the same lock, which can't be taken twice
+    if Arc::ptr_eq(self, struct2) {
+      return Ok(self.new_patch());
+    }
+    // Each side is copied out and its lock released before the other is taken
+    let left = lock_patchwork(self)?.clone();
+    let right = lock_patchwork(struct2)?.clone();
+    left.diff_with_options(&right, options)
+  }
+
+  fn check_patch(&self, patch: &Patch) -> Result<()> {
+    lock_patchwork(self)?.check_patch(patch)
+  }
+
+  fn snapshot(&self) -> Result<Arc<Mutex<T>>> {
+    let copy = lock_patchwork(self)?.snapshot()?;
+    Ok(Arc::new(Mutex::new(copy)))
+  }
+
+  fn restore(&mut self, snapshot: &mut Arc<Mutex<T>>) {
+    if Arc::ptr_eq(self, snapshot) {
+      return;
+    }
+    // Restoring is how a failed apply is cleaned up, so it goes ahead even if a lock was poisoned
+    let mut saved = snapshot
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+      .clone();
+    self
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+      .restore(&mut saved);
+  }
+
+  fn apply(&mut self, patch: &Patch) -> Result<()> {
+    lock_patchwork(self)?.apply(patch)
+  }
+}
+
+/// Take a lock for Patchwork, turning poisoning into an error instead of a panic
+fn lock_patchwork<T>(mutex: &Mutex<T>) -> Result<std::sync::MutexGuard<'_, T>> {
+  mutex
+    .lock()
+    .map_err(|_| ProteanError::PoisonedLock(std::any::type_name::<T>().to_string()).into())
+}
+
+/// Ordered maps are compared by key
+///
+/// Values stored under the same key are diffed against each other, using the key's Display as the path
+/// segment, so keys must not contain a '.'. New entries are added whole and removed entries are marked
+/// with a removal.
+impl<'a, K, V> Patchwork<'a> for BTreeMap<K, V>
+where
+  K: Ord + Clone + Debug + Serialize + Deserialize<'a> + std::fmt::Display + std::str::FromStr,
+  V: Patchwork<'a> + Deserialize<'a>,
+{
+  fn diff(&self, struct2: &BTreeMap<K, V>) -> Result<Patch> {
+    self.diff_with_options(struct2, &DiffOptions::default())
+  }
+
+  fn diff_with_options(&self, struct2: &BTreeMap<K, V>, options: &DiffOptions) -> Result<Patch> {
+    let mut patch = self.new_patch();
+    if options.max_depth == 0 {
+      return Patch::diff_whole(patch, self, struct2);
+    }
+    for key in self.keys().chain(struct2.keys()) {
+      check_segment(&key.to_string(), "BTreeMap keys are used as key segments")?;
+    }
+    let options = options.descend();
+    for (key, right) in struct2.iter() {
+      match self.get(key) {
+        Some(left) => {
+          patch.merge_field(&key.to_string(), left.diff_with_options(right, &options)?)?;
+        }
+        None => {
+          patch.insert(&key.to_string(), &serde_json::to_value(right)?)?;
+        }
+      }
+    }
+    for key in self.keys() {
+      if !struct2.contains_key(key) {
+        patch.remove(&key.to_string())?;
+      }
+    }
+    Ok(patch)
+  }
+
+  fn snapshot(&self) -> Result<BTreeMap<K, V>> {
+    self
+      .iter()
+      .map(|(key, value)| Ok((key.clone(), value.snapshot()?)))
+      .collect()
+  }
+
+  // Matching entries are restored in place, but a change to the keys swaps the whole map back
+  fn restore(&mut self, snapshot: &mut BTreeMap<K, V>) {
+    if self.len() != snapshot.len() || self.keys().zip(snapshot.keys()).any(|(a, b)| a != b) {
+      return std::mem::swap(self, snapshot);
+    }
+    for (value, saved) in self.values_mut().zip(snapshot.values_mut()) {
+      value.restore(saved);
+    }
+  }
+
+  fn apply(&mut self, patch: &Patch) -> Result<()> {
+    if let Some(value) = patch.get(None, "&self") {
+      *self = Patch::deserialize_value("&self", value)?;
+    }
+
+    for child in patch.child_keys() {
+      let key = child
+        .parse::<K>()
+        .map_err(|_| ProteanError::UnknownKey(child.clone()))
+        .context("BTreeMap patch keys must parse into the map's key type")?;
+      let sub_patch = patch.strip_prefix(&child);
+      match (self.get_mut(&key), sub_patch.get_op(None, "&self")) {
+        (Some(_), Some(PatchOp::Remove)) => {
+          self.remove(&key);
+        }
+        (Some(existing), _) => existing.apply(&sub_patch)?,
+        // Already gone, so there is nothing to remove
+        (None, Some(PatchOp::Remove)) => (),
+        (None, Some(PatchOp::Add(value))) | (None, Some(PatchOp::Set(value))) => {
+          self.insert(key, Patch::deserialize_value(&child, value)?);
+        }
+        (None, None) => Err(ProteanError::MissingValue(child.clone()))
+          .context("Cannot patch inside of a map entry that doesn't exist")?,
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Ordered sets are compared by the presence of elements
+///
+/// The patch has up to two keys, `added` and `removed`, each holding a list of the elements that were
+/// inserted into or taken out of the set. Both are stored as an Add so Patch::compose can tell them apart
+/// from a plain list and merge them.
+impl<'a, T> Patchwork<'a> for BTreeSet<T>
+where
+  T: Ord + Clone + Debug + Serialize + Deserialize<'a>,
+{
+  fn diff(&self, struct2: &BTreeSet<T>) -> Result<Patch> {
+    let mut patch = self.new_patch();
+    let added: Vec<&T> = struct2.difference(self).collect();
+    if !added.is_empty() {
+      patch.insert("added", &serde_json::to_value(added)?)?;
+    }
+    let removed: Vec<&T> = self.difference(struct2).collect();
+    if !removed.is_empty() {
+      patch.insert("removed", &serde_json::to_value(removed)?)?;
+    }
+    Ok(patch)
+  }
+
+  fn apply(&mut self, patch: &Patch) -> Result<()> {
+    if let Some(key) = patch
+      .child_keys()
+      .into_iter()
+      .find(|key| key != "added" && key != "removed")
+    {
+      return Err(ProteanError::UnknownKey(key)).context("Sets only accept added and removed");
+    }
+
+    if let Some(value) = patch.get(None, "&self") {
+      *self = Patch::deserialize_value("&self", value)?;
+    }
+    if let Some(value) = patch.get(None, "removed") {
+      let removed: Vec<T> = Patch::deserialize_value("removed", value)?;
+      for item in removed.iter() {
+        self.remove(item);
+      }
+    }
+    if let Some(value) = patch.get(None, "added") {
+      let added: Vec<T> = Patch::deserialize_value("added", value)?;
+      self.extend(added);
+    }
+    Ok(())
+  }
+}
 
 // Doesn't work because there is no clone() for str
 // primitive_patchwork! {str}
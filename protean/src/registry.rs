@@ -0,0 +1,73 @@
+//! Dispatch patches to the type they were made for
+//!
+//! A patch pulled off the wire only knows its patch_type, so the registry keeps a handler for each known
+//! type that downcasts the target and applies the patch to it.
+
+use anyhow::{Context, Result};
+use std::any::Any;
+use std::collections::HashMap;
+
+use crate::{Patch, Patchwork, ProteanError};
+
+type Handler = Box<dyn Fn(&Patch, &mut dyn Any) -> Result<()>>;
+
+/// A lookup from patch_type to the code that applies it
+///
+/// ```ignore
+/// let mut registry = PatchRegistry::new();
+/// registry.register::<User>("User").register::<Account>("Account");
+///
+/// let patch = Patch::from_json(&message)?;
+/// registry.apply(&patch, &mut user)?;
+/// ```
+#[derive(Default)]
+pub struct PatchRegistry {
+  handlers: HashMap<String, Handler>,
+}
+
+impl PatchRegistry {
+  pub fn new() -> PatchRegistry {
+    PatchRegistry::default()
+  }
+
+  /// Route patches with the given patch_type to T
+  ///
+  /// The patch_type needs to match the one set by T's new_patch, which is the struct name when derived.
+  /// Registering the same patch_type again replaces the previous handler.
+  pub fn register<T>(&mut self, patch_type: &str) -> &mut PatchRegistry
+  where
+    T: Patchwork<'static> + 'static,
+  {
+    let expected = patch_type.to_string();
+    let handler = move |patch: &Patch, target: &mut dyn Any| -> Result<()> {
+      let target = target
+        .downcast_mut::<T>()
+        .ok_or(ProteanError::InvalidPatchType)
+        .context(format!(
+          "The target of a '{}' patch must be a {}",
+          expected,
+          std::any::type_name::<T>()
+        ))?;
+      target.apply(patch)
+    };
+    self
+      .handlers
+      .insert(patch_type.to_string(), Box::new(handler));
+    self
+  }
+
+  /// Check if a handler exists for the patch_type
+  pub fn contains(&self, patch_type: &str) -> bool {
+    self.handlers.contains_key(patch_type)
+  }
+
+  /// Apply the patch to the target using the handler registered for its patch_type
+  pub fn apply(&self, patch: &Patch, target: &mut dyn Any) -> Result<()> {
+    let handler = self
+      .handlers
+      .get(patch.patch_type())
+      .ok_or_else(|| ProteanError::UnregisteredPatchType(patch.patch_type().to_string()))
+      .context("Register the type with PatchRegistry::register before applying its patches")?;
+    handler(patch, target)
+  }
+}
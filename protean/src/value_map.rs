@@ -0,0 +1,189 @@
+//! The storage behind a patch
+//!
+//! Most patches only touch one or two fields, so building a full HashMap for each of them is mostly
+//! allocation overhead. ValueMap keeps small patches in a plain vec searched linearly, and only moves
+//! to a HashMap once it grows past INLINE_LIMIT entries.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+
+use crate::PatchOp;
+
+/// The most entries stored inline before switching over to a HashMap
+pub const INLINE_LIMIT: usize = 8;
+
+/// A map of keys to changes that avoids hashing while small
+#[derive(Clone)]
+pub struct ValueMap(Repr);
+
+#[derive(Clone)]
+enum Repr {
+  Inline(Vec<(String, PatchOp)>),
+  Map(HashMap<String, PatchOp>),
+}
+
+impl ValueMap {
+  /// An empty map, which doesn't allocate until the first insert
+  pub fn new() -> ValueMap {
+    ValueMap(Repr::Inline(Vec::new()))
+  }
+
+  pub fn len(&self) -> usize {
+    match &self.0 {
+      Repr::Inline(entries) => entries.len(),
+      Repr::Map(map) => map.len(),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn get(&self, key: &str) -> Option<&PatchOp> {
+    match &self.0 {
+      Repr::Inline(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, op)| op),
+      Repr::Map(map) => map.get(key),
+    }
+  }
+
+  /// Store the change under the key, returning the one it replaced
+  pub fn insert(&mut self, key: String, op: PatchOp) -> Option<PatchOp> {
+    match &mut self.0 {
+      Repr::Inline(entries) => {
+        if let Some((_, existing)) = entries.iter_mut().find(|(k, _)| *k == key) {
+          return Some(std::mem::replace(existing, op));
+        }
+        if entries.len() < INLINE_LIMIT {
+          entries.push((key, op));
+          return None;
+        }
+        let mut map: HashMap<String, PatchOp> = entries.drain(..).collect();
+        map.insert(key, op);
+        self.0 = Repr::Map(map);
+        None
+      }
+      Repr::Map(map) => map.insert(key, op),
+    }
+  }
+
+  pub fn remove(&mut self, key: &str) -> Option<PatchOp> {
+    match &mut self.0 {
+      Repr::Inline(entries) => {
+        let position = entries.iter().position(|(k, _)| k == key)?;
+        Some(entries.remove(position).1)
+      }
+      Repr::Map(map) => map.remove(key),
+    }
+  }
+
+  /// Keep only the entries the filter returns true for
+  pub fn retain<F: FnMut(&String, &PatchOp) -> bool>(&mut self, mut filter: F) {
+    match &mut self.0 {
+      Repr::Inline(entries) => entries.retain(|(key, op)| filter(key, op)),
+      Repr::Map(map) => map.retain(|key, op| filter(key, op)),
+    }
+  }
+
+  pub fn keys(&self) -> impl Iterator<Item = &String> {
+    self.iter().map(|(key, _)| key)
+  }
+
+  /// Walk the entries in no particular order
+  pub fn iter(&self) -> Iter<'_> {
+    match &self.0 {
+      Repr::Inline(entries) => Iter::Inline(entries.iter()),
+      Repr::Map(map) => Iter::Map(map.iter()),
+    }
+  }
+}
+
+impl Default for ValueMap {
+  fn default() -> ValueMap {
+    ValueMap::new()
+  }
+}
+
+/// Borrowing iterator over a ValueMap
+pub enum Iter<'a> {
+  Inline(std::slice::Iter<'a, (String, PatchOp)>),
+  Map(std::collections::hash_map::Iter<'a, String, PatchOp>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+  type Item = (&'a String, &'a PatchOp);
+
+  fn next(&mut self) -> Option<Self::Item> {
+    match self {
+      Iter::Inline(entries) => entries.next().map(|(key, op)| (key, op)),
+      Iter::Map(map) => map.next(),
+    }
+  }
+}
+
+/// Owning iterator over a ValueMap
+pub enum IntoIter {
+  Inline(std::vec::IntoIter<(String, PatchOp)>),
+  Map(std::collections::hash_map::IntoIter<String, PatchOp>),
+}
+
+impl Iterator for IntoIter {
+  type Item = (String, PatchOp);
+
+  fn next(&mut self) -> Option<Self::Item> {
+    match self {
+      IntoIter::Inline(entries) => entries.next(),
+      IntoIter::Map(map) => map.next(),
+    }
+  }
+}
+
+impl IntoIterator for ValueMap {
+  type Item = (String, PatchOp);
+  type IntoIter = IntoIter;
+
+  fn into_iter(self) -> IntoIter {
+    match self.0 {
+      Repr::Inline(entries) => IntoIter::Inline(entries.into_iter()),
+      Repr::Map(map) => IntoIter::Map(map.into_iter()),
+    }
+  }
+}
+
+impl std::iter::FromIterator<(String, PatchOp)> for ValueMap {
+  fn from_iter<I: IntoIterator<Item = (String, PatchOp)>>(iter: I) -> ValueMap {
+    let mut map = ValueMap::new();
+    for (key, op) in iter {
+      map.insert(key, op);
+    }
+    map
+  }
+}
+
+/// Equal when both hold the same changes, no matter how they are stored
+impl PartialEq for ValueMap {
+  fn eq(&self, other: &ValueMap) -> bool {
+    self.len() == other.len() && self.iter().all(|(key, op)| other.get(key) == Some(op))
+  }
+}
+
+impl Eq for ValueMap {}
+
+impl std::fmt::Debug for ValueMap {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_map().entries(self.iter()).finish()
+  }
+}
+
+/// Serialized as a plain map, so the storage used doesn't leak into the JSON
+impl Serialize for ValueMap {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(self.iter())
+  }
+}
+
+impl<'de> Deserialize<'de> for ValueMap {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ValueMap, D::Error> {
+    let map = HashMap::<String, PatchOp>::deserialize(deserializer)?;
+    Ok(map.into_iter().collect())
+  }
+}
@@ -0,0 +1,459 @@
+//! Derive macros for Protean
+//!
+//! Generates the Patchwork boilerplate for a struct, diffing and applying each field in turn using that
+//! field's own implementation of Patchwork.
+//!
+//! Generic structs are supported, with a Patchwork bound added for the type of each field that isn't
+//! skipped, so a parameter only needs whatever its containers require of it.
+//!
+//! Field attributes:
+//! - `#[patch(rename = "name")]` Use a different key for the field in the patch
+//! - `#[patch(skip)]` Leave the field out of both diff and apply
+//! - `#[patch(flatten)]` Put the keys of a nested struct at the top level, like serde's flatten. Keys
+//!   that collide with another field are a DuplicateKey error on the first diff or apply.
+//!
+//! The error messages are covered by the compile_fail tests in test_suite/tests/ui.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{TokenStream as TokenStream2, TokenTree};
+use quote::{quote, quote_spanned, ToTokens};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Lit, Meta, NestedMeta};
+
+#[proc_macro_derive(Patchwork, attributes(patch))]
+pub fn derive_patchwork(input: TokenStream) -> TokenStream {
+  let input = parse_macro_input!(input as DeriveInput);
+  expand_patchwork(&input)
+    .unwrap_or_else(|err| err.to_compile_error())
+    .into()
+}
+
+/// A field of the struct along with the options set by its attributes
+struct PatchField {
+  /// How the field is accessed on the struct (name or tuple index)
+  member: syn::Member,
+
+  /// The key used for the field inside of a patch
+  key: String,
+
+  /// The declared type, used to find which generic parameters need a bound
+  ty: syn::Type,
+
+  /// Leave the field out of the patch entirely
+  skip: bool,
+
+  /// Merge the field's keys into the struct's own instead of nesting them under the field's key
+  flatten: bool,
+}
+
+impl PatchField {
+  fn from_field(index: usize, field: &syn::Field) -> syn::Result<PatchField> {
+    let member = match &field.ident {
+      Some(ident) => syn::Member::Named(ident.clone()),
+      None => syn::Member::Unnamed(syn::Index::from(index)),
+    };
+    let mut result = PatchField {
+      key: match &member {
+        syn::Member::Named(ident) => ident.to_string(),
+        syn::Member::Unnamed(index) => index.index.to_string(),
+      },
+      member,
+      ty: field.ty.clone(),
+      skip: false,
+      flatten: false,
+    };
+
+    for attr in field
+      .attrs
+      .iter()
+      .filter(|attr| attr.path.is_ident("patch"))
+    {
+      let list = match attr.parse_meta()? {
+        Meta::List(list) => list,
+        meta => {
+          return Err(syn::Error::new_spanned(
+            meta,
+            "expected #[patch(...)] with a list of options",
+          ))
+        }
+      };
+
+      for nested in list.nested.iter() {
+        match nested {
+          NestedMeta::Meta(Meta::Path(path)) if path.is_ident("skip") => result.skip = true,
+          NestedMeta::Meta(Meta::Path(path)) if path.is_ident("flatten") => result.flatten = true,
+          NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("rename") => {
+            match &pair.lit {
+              Lit::Str(name) => result.key = name.value(),
+              lit => return Err(syn::Error::new_spanned(lit, "rename must be a string")),
+            }
+          }
+          _ => {
+            return Err(syn::Error::new_spanned(
+              nested,
+              "unknown patch option, expected `rename = \"...\"`, `skip` or `flatten`",
+            ))
+          }
+        }
+      }
+    }
+
+    if result.flatten && result.key != result.member.to_token_stream().to_string() {
+      return Err(syn::Error::new_spanned(
+        field,
+        "a flattened field has no key of its own, so it can't be renamed",
+      ));
+    }
+    Ok(result)
+  }
+}
+
+/// Add the Patchwork lifetime to the struct's generics, along with a bound on each field's type
+///
+/// Patchwork doesn't require Deserialize, but the generated apply does to replace the whole struct. The
+/// struct's own Clone, Debug and Serialize are bounded too, since std's derives only implement them when
+/// every parameter does, even one that is only used as a marker.
+///
+/// Bounding the field types rather than the type parameters lets containers such as `Vec<T>` bring
+/// their own requirements on `T`, and a PhantomData marker is Patchwork for any parameter.
+fn patchwork_generics(input: &DeriveInput, fields: &[&PatchField]) -> syn::Generics {
+  let mut generics = input.generics.clone();
+  generics.params.insert(0, parse_quote!('__protean));
+
+  let name = &input.ident;
+  let (_, type_generics, _) = input.generics.split_for_impl();
+  let where_clause = generics.make_where_clause();
+  where_clause
+    .predicates
+    .push(parse_quote!(#name #type_generics: ::protean::__private::Deserialize<'__protean>));
+  where_clause.predicates.push(parse_quote!(
+    #name #type_generics: ::std::clone::Clone + ::std::fmt::Debug + ::protean::__private::Serialize
+  ));
+  for field in fields {
+    let ty = &field.ty;
+    let bound = quote_spanned!(ty.span()=> ::protean::Patchwork<'__protean>);
+    where_clause.predicates.push(parse_quote!(#ty: #bound));
+  }
+  generics
+}
+
+/// Check if the tokens use any of the struct's generic parameters, including inside of nested groups
+fn mentions_generics(tokens: TokenStream2, generics: &syn::Generics) -> bool {
+  tokens.into_iter().any(|token| match token {
+    TokenTree::Ident(found) if found == "Self" => true,
+    TokenTree::Ident(found) => generics.params.iter().any(|param| match param {
+      syn::GenericParam::Type(param) => found == param.ident,
+      syn::GenericParam::Lifetime(param) => found == param.lifetime.ident,
+      syn::GenericParam::Const(param) => found == param.ident,
+    }),
+    TokenTree::Group(group) => mentions_generics(group.stream(), generics),
+    _ => false,
+  })
+}
+
+/// Require Patchwork of every field type that doesn't depend on the struct's parameters
+///
+/// A where clause only fails once the struct is used, so without this a field missing an impl compiles
+/// until some other crate calls diff. Each check is spanned to the field's type, so the error is
+/// reported once at the field.
+fn assert_fields(input: &DeriveInput, fields: &[&PatchField]) -> TokenStream2 {
+  let checks = fields
+    .iter()
+    .map(|field| &field.ty)
+    .filter(|ty| !mentions_generics(ty.to_token_stream(), &input.generics))
+    .map(|ty| quote_spanned!(ty.span()=> assert_patchwork::<#ty>();));
+  quote! {
+    #[allow(dead_code)]
+    const _: () = {
+      fn assert_patchwork<'__protean, T: ::protean::Patchwork<'__protean>>() {}
+      fn assert_fields() {
+        #(#checks)*
+      }
+    };
+  }
+}
+
+fn expand_patchwork(input: &DeriveInput) -> syn::Result<TokenStream2> {
+  let fields: Vec<&syn::Field> = match &input.data {
+    Data::Struct(data) => match &data.fields {
+      Fields::Named(named) => named.named.iter().collect(),
+      Fields::Unnamed(unnamed) => unnamed.unnamed.iter().collect(),
+      Fields::Unit => Vec::new(),
+    },
+    Data::Enum(_) => {
+      return Err(syn::Error::new_spanned(
+        &input.ident,
+        "Patchwork cannot be derived for enums yet",
+      ))
+    }
+    Data::Union(_) => {
+      return Err(syn::Error::new_spanned(
+        &input.ident,
+        "Patchwork cannot be derived for unions",
+      ))
+    }
+  };
+
+  let fields = fields
+    .iter()
+    .enumerate()
+    .map(|(index, field)| PatchField::from_field(index, field))
+    .collect::<syn::Result<Vec<PatchField>>>()?;
+  let skipped: Vec<&syn::Member> = fields
+    .iter()
+    .filter(|field| field.skip)
+    .map(|field| &field.member)
+    .collect();
+  let fields: Vec<&PatchField> = fields.iter().filter(|field| !field.skip).collect();
+
+  let name = &input.ident;
+  let type_name = name.to_string();
+  let generics = patchwork_generics(input, &fields);
+  let assertions = assert_fields(input, &fields);
+  let (impl_generics, _, where_clause) = generics.split_for_impl();
+  let (_, type_generics, _) = input.generics.split_for_impl();
+  let (flattened, fields): (Vec<&PatchField>, Vec<&PatchField>) =
+    fields.into_iter().partition(|field| field.flatten);
+  let members: Vec<&syn::Member> = fields.iter().map(|field| &field.member).collect();
+  let keys: Vec<&String> = fields.iter().map(|field| &field.key).collect();
+  let flat_members: Vec<&syn::Member> = flattened.iter().map(|field| &field.member).collect();
+  // Snapshots and restores treat plain and flattened fields the same
+  let patched: Vec<&syn::Member> = members.iter().chain(flat_members.iter()).copied().collect();
+
+  // An empty or-pattern isn't valid, so only match the keys when there are fields to patch
+  let known_keys = match keys.is_empty() {
+    true => quote! {},
+    false => quote! { #(#keys)|* => (), },
+  };
+
+  // Flattened keys only exist at runtime, so they are checked for collisions and matched as they are used
+  let (check_flattened, flattened_keys) = match flattened.is_empty() {
+    true => (quote! {}, quote! {}),
+    false => (
+      quote! {
+        ::protean::__private::unique_keys(::protean::Patchwork::field_keys(self))?;
+      },
+      quote! {
+        _ if false #(|| ::protean::Patchwork::field_keys(&self.#flat_members).contains(&key))* => (),
+      },
+    ),
+  };
+
+  // Skipped fields are kept when the whole struct is replaced, so they are left out of the comparison too
+  let diff_whole = match skipped.is_empty() {
+    true => quote! { ::protean::Patch::diff_whole(patch, self, struct2) },
+    false => quote! {{
+      let (mut left, mut right) = (self.clone(), struct2.clone());
+      #(
+        ::std::mem::swap(&mut left.#skipped, &mut right.#skipped);
+      )*
+      ::protean::Patch::diff_whole(patch, self, &right)
+    }},
+  };
+
+  Ok(quote! {
+    #assertions
+
+    impl #impl_generics ::protean::Patchwork<'__protean> for #name #type_generics #where_clause {
+      fn new_patch(&self) -> ::protean::Patch {
+        let validator = ::protean::FieldValidator::new(
+          vec![#((#keys.to_string(), ::protean::Patchwork::new_patch(&self.#members).validator())),*],
+          vec![#(::protean::Patchwork::new_patch(&self.#flat_members).validator()),*],
+        );
+        ::protean::Patch::new(#type_name).with_validator(::std::rc::Rc::new(validator))
+      }
+
+      fn field_keys(&self) -> ::std::vec::Vec<::std::string::String> {
+        #[allow(unused_mut)]
+        let mut keys: ::std::vec::Vec<::std::string::String> = vec![#(#keys.to_string()),*];
+        #(
+          keys.extend(::protean::Patchwork::field_keys(&self.#flat_members));
+        )*
+        keys
+      }
+
+      fn diff(&self, struct2: &Self) -> ::protean::__private::Result<::protean::Patch> {
+        ::protean::Patchwork::diff_with_options(self, struct2, &::protean::DiffOptions::default())
+      }
+
+      fn snapshot(&self) -> ::protean::__private::Result<Self> {
+        #[allow(unused_mut)]
+        let mut copy = self.clone();
+        #(
+          copy.#patched = ::protean::Patchwork::snapshot(&self.#patched)?;
+        )*
+        Ok(copy)
+      }
+
+      fn restore(&mut self, snapshot: &mut Self) {
+        #(
+          ::protean::Patchwork::restore(&mut self.#patched, &mut snapshot.#patched);
+        )*
+        // Skipped fields are swapped back as well, so nothing that changed since the snapshot is kept
+        #(
+          ::std::mem::swap(&mut self.#skipped, &mut snapshot.#skipped);
+        )*
+      }
+
+      fn diff_with_options(
+        &self,
+        struct2: &Self,
+        options: &::protean::DiffOptions,
+      ) -> ::protean::__private::Result<::protean::Patch> {
+        ::protean::__private::trace_diff(#type_name, || {
+          #check_flattened
+          #[allow(unused_mut)]
+          let mut patch = self.new_patch();
+          if options.max_depth == 0 {
+            return #diff_whole;
+          }
+          // Flattened fields sit at the same level as the struct, so they get the depth it was given
+          #(
+            patch.merge_flattened(
+              ::protean::Patchwork::diff_with_options(&self.#flat_members, &struct2.#flat_members, options)?,
+            )?;
+          )*
+          #[allow(unused_variables)]
+          let options = options.descend();
+          #(
+            patch.merge_field(
+              #keys,
+              ::protean::Patchwork::diff_with_options(&self.#members, &struct2.#members, &options)?,
+            )?;
+          )*
+          Ok(patch)
+        })
+      }
+
+      fn diff_visit(
+        &self,
+        struct2: &Self,
+        visitor: &mut dyn FnMut(&str, &::protean::PatchOp) -> ::protean::__private::Result<()>,
+      ) -> ::protean::__private::Result<()> {
+        #check_flattened
+        #(
+          ::protean::Patchwork::diff_visit(&self.#flat_members, &struct2.#flat_members, &mut *visitor)?;
+        )*
+        #(
+          ::protean::Patchwork::diff_visit(
+            &self.#members,
+            &struct2.#members,
+            &mut |key: &str, op: &::protean::PatchOp| {
+              visitor(&::protean::Patch::prefix_key(#keys, key), op)
+            },
+          )?;
+        )*
+        Ok(())
+      }
+
+      fn apply_changes(
+        &mut self,
+        patch: &::protean::Patch,
+      ) -> ::protean::__private::Result<::std::vec::Vec<::std::string::String>> {
+        #check_flattened
+        for key in patch.child_keys() {
+          match &key[..] {
+            #known_keys
+            #flattened_keys
+            _ => return Err(::protean::ProteanError::UnknownKey(key.clone()).into()),
+          }
+        }
+
+        #[allow(unused_mut)]
+        let mut changed = ::std::vec::Vec::new();
+        if let Some(value) = patch.get(None, "&self") {
+          let before = self.clone();
+          #[allow(unused_mut)]
+          let mut replaced: Self = ::protean::Patch::deserialize_value("&self", value)?;
+          #(
+            ::std::mem::swap(&mut replaced.#skipped, &mut self.#skipped);
+          )*
+          *self = replaced;
+          let replaced = ::protean::Patchwork::diff(&before, self)?;
+          changed.extend(replaced.iter().map(|(key, _)| key.clone()));
+        }
+        #(
+          let sub_patch = patch.select_fields(&::protean::Patchwork::field_keys(&self.#flat_members));
+          if !sub_patch.is_empty() {
+            changed.extend(::protean::Patchwork::apply_changes(&mut self.#flat_members, &sub_patch)?);
+          }
+        )*
+        #(
+          let sub_patch = patch.strip_prefix(#keys);
+          if !sub_patch.is_empty() {
+            let fields = ::protean::Patchwork::apply_changes(&mut self.#members, &sub_patch)?;
+            changed.extend(fields.iter().map(|key| ::protean::Patch::prefix_key(#keys, key)));
+          }
+        )*
+        changed.sort();
+        changed.dedup();
+        Ok(changed)
+      }
+
+      fn check_patch(&self, patch: &::protean::Patch) -> ::protean::__private::Result<()> {
+        #check_flattened
+        for key in patch.child_keys() {
+          match &key[..] {
+            #known_keys
+            #flattened_keys
+            _ => return Err(::protean::ProteanError::UnknownKey(key.clone()).into()),
+          }
+        }
+
+        if let Some(value) = patch.get(None, "&self") {
+          let _: Self = ::protean::Patch::deserialize_value("&self", value)?;
+        }
+        #(
+          let sub_patch = patch.select_fields(&::protean::Patchwork::field_keys(&self.#flat_members));
+          if !sub_patch.is_empty() {
+            ::protean::Patchwork::check_patch(&self.#flat_members, &sub_patch)?;
+          }
+        )*
+        #(
+          let sub_patch = patch.strip_prefix(#keys);
+          if !sub_patch.is_empty() {
+            ::protean::Patchwork::check_patch(&self.#members, &sub_patch)?;
+          }
+        )*
+        Ok(())
+      }
+
+      fn apply(&mut self, patch: &::protean::Patch) -> ::protean::__private::Result<()> {
+        ::protean::__private::trace_apply(patch, || {
+          #check_flattened
+          for key in patch.child_keys() {
+            match &key[..] {
+              #known_keys
+              #flattened_keys
+              _ => return Err(::protean::ProteanError::UnknownKey(key.clone()).into()),
+            }
+          }
+
+          if let Some(value) = patch.get(None, "&self") {
+            #[allow(unused_mut)]
+            let mut replaced: Self = ::protean::Patch::deserialize_value("&self", value)?;
+            #(
+              ::std::mem::swap(&mut replaced.#skipped, &mut self.#skipped);
+            )*
+            *self = replaced;
+          }
+          #(
+            let sub_patch = patch.select_fields(&::protean::Patchwork::field_keys(&self.#flat_members));
+            if !sub_patch.is_empty() {
+              ::protean::Patchwork::apply(&mut self.#flat_members, &sub_patch)?;
+            }
+          )*
+          #(
+            let sub_patch = patch.strip_prefix(#keys);
+            if !sub_patch.is_empty() {
+              ::protean::Patchwork::apply(&mut self.#members, &sub_patch)?;
+            }
+          )*
+          Ok(())
+        })
+      }
+    }
+  })
+}
@@ -20,7 +20,7 @@
 use std::sync::Once;
 static LOGGING: Once = Once::new();
 
-use protean::{patch, Patchwork};
+use protean::{patch, PatchOp, Patchwork};
 use tyrell::register;
 
 /// Set up that should be run for each ea
@@ -98,16 +98,30 @@ mod tools {
   // TODO: Convert this to a macro
   impl<'a> Patchwork<'a> for Tester {
     fn diff(&self, struct2: &Tester) -> Result<Patch> {
-      let patch = self
-        .new_patch()
-        .merge("integer", self.integer.diff(&struct2.integer)?)?
-        .merge("float", self.float.diff(&struct2.float)?)?
-        .merge("string", self.string.diff(&struct2.string)?)?
-        .merge("nested", self.nested.diff(&struct2.nested)?)?
-        .merge("unkeyed", self.unkeyed.diff(&struct2.unkeyed)?)?;
+      let mut patch = self.new_patch();
+      patch
+        .merge_field("integer", self.integer.diff(&struct2.integer)?)?
+        .merge_field("float", self.float.diff(&struct2.float)?)?
+        .merge_field("string", self.string.diff(&struct2.string)?)?
+        .merge_fields(vec![
+          ("nested", self.nested.diff(&struct2.nested)?),
+          ("unkeyed", self.unkeyed.diff(&struct2.unkeyed)?),
+        ])?;
       Ok(patch)
     }
 
+    fn apply(&mut self, patch: &Patch) -> Result<()> {
+      if let Some(value) = patch.get(None, "&self") {
+        *self = Patch::deserialize_value("&self", value)?;
+      }
+      self.integer.apply(&patch.strip_prefix("integer"))?;
+      self.float.apply(&patch.strip_prefix("float"))?;
+      self.string.apply(&patch.strip_prefix("string"))?;
+      self.nested.apply(&patch.strip_prefix("nested"))?;
+      self.unkeyed.apply(&patch.strip_prefix("unkeyed"))?;
+      Ok(())
+    }
+
     fn to_patch(&self) -> Result<Patch> {
       unimplemented!("'UnitTest Tester::to_patch' still needs to be implemented")
     }
@@ -162,11 +176,40 @@ mod tools {
       Ok(patch)
     }
 
+    fn apply(&mut self, patch: &Patch) -> Result<()> {
+      if let Some(value) = patch.get(None, "&self") {
+        *self = Patch::deserialize_value("&self", value)?;
+      }
+      self.level_2.apply(&patch.strip_prefix("level_2"))
+    }
+
     fn to_patch(&self) -> Result<Patch> {
       unimplemented!("'UnitTest Nested::to_patch' still needs to be implemented")
     }
   }
 
+  /// A struct using the derive, covering the field attributes
+  #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+  pub struct Derived {
+    #[patch(skip)]
+    pub pk: uuid::Uuid,
+    #[patch(rename = "first_name")]
+    pub name: String,
+    pub age: u8,
+    pub tester: Tester,
+  }
+
+  impl Derived {
+    pub fn random() -> Derived {
+      Derived {
+        pk: uuid::Uuid::new_v4(),
+        name: "Dave".to_string(),
+        age: rand::thread_rng().gen(),
+        tester: Tester::random(),
+      }
+    }
+  }
+
   /// An unkeyed struct for testing some Replicant functionality
   #[derive(Debug, Clone, Serialize, Deserialize)]
   pub struct Unkeyed {
@@ -201,6 +244,13 @@ mod tools {
       Ok(patch)
     }
 
+    fn apply(&mut self, patch: &Patch) -> Result<()> {
+      if let Some(value) = patch.get(None, "&self") {
+        *self = Patch::deserialize_value("&self", value)?;
+      }
+      self.something.apply(&patch.strip_prefix("something"))
+    }
+
     fn to_patch(&self) -> Result<Patch> {
       unimplemented!("'UnitTest Unkeyed::to_patch' still needs to be implemented")
     }
@@ -243,6 +293,460 @@ test!(
   }
 );
 
+test!(
+  fn test_display() {
+    let tester = tools::Tester::default();
+    let patch = patch!(tester, (("string", "Portland")), (("integer", 1)));
+    log::debug!("Displaying the patch:\n{}", patch);
+
+    // One line per key, sorted so the output is stable
+    assert_eq!(
+      format!("{}", patch),
+      "~ integer: 1\n~ string: \"Portland\"\n"
+    );
+  }
+);
+
+test!(
+  fn test_derive_flatten() {
+    use protean::ProteanError;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct AuditFields {
+      created_by: String,
+      updated_by: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Record {
+      name: String,
+      #[patch(flatten)]
+      audit: AuditFields,
+    }
+
+    let base = Record {
+      name: "first".to_string(),
+      audit: AuditFields {
+        created_by: "dave".to_string(),
+        updated_by: "dave".to_string(),
+      },
+    };
+    let mut updated = base.clone();
+    updated.name = "second".to_string();
+    updated.audit.updated_by = "someone".to_string();
+
+    // The audit fields show up next to the record's own
+    let patch = base.diff(&updated).unwrap();
+    assert_eq!(patch.child_keys(), vec!["name", "updated_by"]);
+    assert_eq!(base.field_keys(), vec!["name", "created_by", "updated_by"]);
+    let mut applied = base.clone();
+    assert_eq!(
+      applied.apply_changes(&patch).unwrap(),
+      vec!["name", "updated_by"]
+    );
+    assert_eq!(applied.audit.updated_by, "someone");
+    assert!(applied.apply(&patch.prefix_keys("audit")).is_err());
+
+    // A key used by both is found even when neither of them changed
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Clash {
+      created_by: String,
+      #[patch(flatten)]
+      audit: AuditFields,
+    }
+    let clash = Clash {
+      created_by: "dave".to_string(),
+      audit: base.audit.clone(),
+    };
+    let err = clash.diff(&clash.clone()).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<ProteanError>(),
+      Some(ProteanError::DuplicateKey(key)) if key == "created_by"
+    ));
+  }
+);
+
+test!(
+  fn test_derive_generics() {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Wrapper<T> {
+      inner: T,
+      version: u64,
+    }
+
+    let base = Wrapper {
+      inner: tools::Tester::default(),
+      version: 1,
+    };
+    let mut updated = base.clone();
+    updated.inner.integer = 2;
+    updated.version = 2;
+
+    let patch = base.diff(&updated).unwrap();
+    assert_eq!(patch.child_keys(), vec!["inner", "version"]);
+    assert_eq!(
+      patch.get(Some("inner".to_string()), "integer"),
+      Some(&serde_json::json!(2))
+    );
+
+    let mut applied = base.clone();
+    applied.apply(&patch).unwrap();
+    assert!(applied.diff(&updated).unwrap().is_empty());
+
+    // Any Patchwork type can be wrapped
+    let wrapped = Wrapper {
+      inner: 1.5,
+      version: 0,
+    };
+    let patch = wrapped
+      .diff(&Wrapper {
+        inner: 2.5,
+        version: 0,
+      })
+      .unwrap();
+    assert_eq!(format!("{}", patch), "~ inner: 2.5\n");
+
+    // Containers bring their own bounds on the parameter
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Listing<T> {
+      items: Vec<T>,
+      featured: Option<T>,
+    }
+
+    let listing = Listing {
+      items: vec![1u32, 2],
+      featured: None,
+    };
+    let mut updated = listing.clone();
+    updated.items.push(3);
+    updated.featured = Some(3);
+    let patch = listing.diff(&updated).unwrap();
+    assert_eq!(patch.child_keys(), vec!["featured", "items"]);
+    let mut applied = listing.clone();
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied.items, vec![1, 2, 3]);
+    assert_eq!(applied.featured, Some(3));
+  }
+);
+
+test!(
+  fn test_patch_value() {
+    let base = tools::Tester::default();
+    let mut updated = base.clone();
+    updated.integer = 7;
+    updated.string = "Quoted \"text\"".to_string();
+    let patch = base.diff(&updated).unwrap();
+
+    // Values nest as JSON instead of being strings inside of strings
+    let value = patch.to_value().unwrap();
+    assert_eq!(
+      value["value_map"]["string"],
+      serde_json::json!({ "Set": "Quoted \"text\"" })
+    );
+    assert_eq!(value["value_map"]["integer"]["Set"], serde_json::json!(7));
+    assert_eq!(protean::Patch::from_value(value).unwrap(), patch);
+
+    // And going through a string gives the same thing back
+    let json = patch.to_json().unwrap();
+    assert_eq!(protean::Patch::from_json(&json).unwrap(), patch);
+    assert!(json.len() <= patch.byte_size());
+  }
+);
+
+test!(
+  fn test_patch_try_from() {
+    use protean::{Patch, ProteanError};
+    use std::convert::TryFrom;
+
+    let base = tools::Tester::default();
+    let mut updated = base.clone();
+    updated.string = "sent".to_string();
+    let patch = base.diff(&updated).unwrap();
+
+    // Straight into an envelope and back out again
+    let envelope = serde_json::json!({
+      "topic": "tester",
+      "patch": serde_json::Value::try_from(&patch).unwrap(),
+    });
+    let received = Patch::try_from(&envelope["patch"]).unwrap();
+    assert_eq!(received, patch);
+    assert_eq!(
+      serde_json::Value::try_from(patch.clone()).unwrap(),
+      envelope["patch"]
+    );
+
+    let err = Patch::try_from(&envelope["topic"]).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<ProteanError>(),
+      Some(ProteanError::MalformedPatch(_))
+    ));
+  }
+);
+
+test!(
+  fn test_apply_to_json() {
+    use protean::Patch;
+    use std::collections::BTreeMap;
+
+    // Applying to the serialized value matches applying to the struct
+    let base = tools::Tester::random();
+    let mut updated = base.clone();
+    updated.integer += 1;
+    updated.string = "changed".to_string();
+    let patch = base.diff(&updated).unwrap();
+    let mut value = serde_json::to_value(&base).unwrap();
+    patch.apply_to_json(&mut value).unwrap();
+    assert_eq!(value, serde_json::to_value(&updated).unwrap());
+
+    // Arrays grow and shrink at the end, with indices compared as numbers
+    let before: Vec<u8> = (0..9).collect();
+    let after: Vec<u8> = (0..12).collect();
+    let mut value = serde_json::to_value(&before).unwrap();
+    before
+      .diff(&after)
+      .unwrap()
+      .apply_to_json(&mut value)
+      .unwrap();
+    assert_eq!(value, serde_json::to_value(&after).unwrap());
+    after
+      .diff(&before)
+      .unwrap()
+      .apply_to_json(&mut value)
+      .unwrap();
+    assert_eq!(value, serde_json::to_value(&before).unwrap());
+
+    // Map entries are added and removed
+    let mut left = BTreeMap::new();
+    left.insert("keep".to_string(), 1);
+    left.insert("drop".to_string(), 2);
+    let mut right = left.clone();
+    right.remove("drop");
+    right.insert("new".to_string(), 3);
+    let mut value = serde_json::to_value(&left).unwrap();
+    left
+      .diff(&right)
+      .unwrap()
+      .apply_to_json(&mut value)
+      .unwrap();
+    assert_eq!(value, serde_json::to_value(&right).unwrap());
+
+    // Missing objects are created on the way down, and a failure leaves the value alone
+    let mut patch = Patch::new("Gateway");
+    patch
+      .add(&"a.b.c".to_string(), &serde_json::json!(1))
+      .unwrap();
+    let mut value = serde_json::json!({});
+    patch.apply_to_json(&mut value).unwrap();
+    assert_eq!(value, serde_json::json!({ "a": { "b": { "c": 1 } } }));
+    patch.remove("missing").unwrap();
+    assert!(patch.apply_to_json(&mut value).is_err());
+    assert_eq!(value, serde_json::json!({ "a": { "b": { "c": 1 } } }));
+  }
+);
+
+test!(
+  fn test_json_value() {
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Row {
+      id: u32,
+      extra: serde_json::Value,
+    }
+
+    let before = Row {
+      id: 1,
+      extra: json!({ "color": "red", "size": { "w": 1, "h": 2 }, "tags": ["a"], "old": true }),
+    };
+    let mut after = before.clone();
+    after.extra =
+      json!({ "color": "red", "size": { "w": 1, "h": 3 }, "tags": ["a", "b"], "new": null });
+
+    // Only the parts of the blob that changed are in the patch
+    let patch = before.diff(&after).unwrap();
+    assert_eq!(
+      format!("{}", patch),
+      "+ extra.new: null\n- extra.old\n~ extra.size.h: 3\n+ extra.tags.1: \"b\"\n"
+    );
+    let mut applied = before.clone();
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied.extra, after.extra);
+
+    // A change of kind replaces the value, as does running out of depth
+    let patch = json!({ "a": 1 }).diff(&json!([1])).unwrap();
+    assert_eq!(patch.get(None, "&self"), Some(&json!([1])));
+    let shallow = protean::DiffOptions { max_depth: 0 };
+    let patch = before
+      .extra
+      .diff_with_options(&after.extra, &shallow)
+      .unwrap();
+    assert_eq!(patch.get(None, "&self"), Some(&after.extra));
+    assert!(json!(1).diff(&json!(1)).unwrap().is_empty());
+
+    // A key with a '.' can't be a single segment of a patch key
+    let err = json!({ "a.b": 1 }).diff(&json!({ "a.b": 2 })).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<protean::ProteanError>(),
+      Some(protean::ProteanError::MalformedPatch(_))
+    ));
+  }
+);
+
+test!(
+  fn test_json_patch() {
+    use protean::Patch;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    let mut patch = Patch::new("Gateway");
+    patch
+      .add(&"address.city".to_string(), &json!("Boston"))
+      .unwrap();
+    patch.insert("tags.a/b~c", &json!(1)).unwrap();
+    patch.remove("orders.3").unwrap();
+    patch.remove("orders.10").unwrap();
+
+    let pointers = patch.json_pointer_keys();
+    assert_eq!(pointers["address.city"], "/address/city");
+    assert_eq!(pointers["tags.a/b~c"], "/tags/a~1b~0c");
+    assert_eq!(pointers["orders.10"], "/orders/10");
+
+    // Removals go last, from the highest index down
+    assert_eq!(
+      patch.to_json_patch(),
+      vec![
+        json!({ "op": "replace", "path": "/address/city", "value": "Boston" }),
+        json!({ "op": "add", "path": "/tags/a~1b~0c", "value": 1 }),
+        json!({ "op": "remove", "path": "/orders/10" }),
+        json!({ "op": "remove", "path": "/orders/3" }),
+      ]
+    );
+
+    // Replacing the whole value points at the root
+    let left: BTreeMap<String, i32> = BTreeMap::new();
+    let whole = Patch::diff_whole(Patch::new("Map"), &left, &vec![1]).unwrap();
+    assert_eq!(whole.json_pointer_keys()["&self"], "");
+    assert_eq!(whole.to_json_patch()[0]["op"], json!("replace"));
+  }
+);
+
+test!(
+  fn test_malformed_json() {
+    use protean::{Patch, ProteanError};
+
+    let bad = vec![
+      "",
+      "not json",
+      "[]",
+      r#"{"value_map": {}}"#,
+      r#"{"patch_type": 5, "value_map": {}}"#,
+      r#"{"patch_type": "Tester", "value_map": []}"#,
+      r#"{"patch_type": "Tester", "value_map": {}, "extra": true}"#,
+      r#"{"patch_type": "Tester", "value_map": {"integer": 5}}"#,
+      r#"{"patch_type": "Tester", "value_map": {"nested..level_2": {"Set": 5}}}"#,
+    ];
+    for json in bad {
+      let err = Patch::from_json(json).unwrap_err();
+      assert!(
+        matches!(
+          err.downcast_ref::<ProteanError>(),
+          Some(ProteanError::MalformedPatch(_))
+        ),
+        "'{}' gave {:?}",
+        json,
+        err
+      );
+    }
+
+    let patch =
+      Patch::from_json(r#"{"patch_type": "Tester", "value_map": {"integer": {"Set": 5}}}"#)
+        .unwrap();
+    assert_eq!(patch.get(None, "integer"), Some(&serde_json::json!(5)));
+  }
+);
+
+test!(
+  fn test_large_patch() {
+    use protean::Patch;
+
+    // Patches move their storage over to a HashMap as they grow, which shouldn't be visible from outside
+    let mut small = Patch::new("Large");
+    let mut large = Patch::new("Large");
+    for i in 0..protean::value_map::INLINE_LIMIT * 3 {
+      let key = format!("field_{}", i);
+      large.add(&key, &serde_json::json!(i)).unwrap();
+      if i < 2 {
+        small.add(&key, &serde_json::json!(i)).unwrap();
+      }
+    }
+
+    assert_eq!(large.iter().count(), protean::value_map::INLINE_LIMIT * 3);
+    assert_eq!(large.get(None, "field_20"), Some(&serde_json::json!(20)));
+    assert_eq!(Patch::from_json(&large.to_json().unwrap()).unwrap(), large);
+    assert_eq!(Patch::from_json(&small.to_json().unwrap()).unwrap(), small);
+    assert_ne!(small, large);
+  }
+);
+
+test!(
+  fn test_zero_sized() {
+    use serde::{Deserialize, Serialize};
+    use std::marker::PhantomData;
+
+    #[derive(Debug, Clone)]
+    struct Locked;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Door<State> {
+      name: String,
+      nothing: (),
+      state: PhantomData<State>,
+    }
+
+    let door: Door<Locked> = Door {
+      name: "Front".to_string(),
+      nothing: (),
+      state: PhantomData,
+    };
+    let mut renamed = door.clone();
+    renamed.name = "Back".to_string();
+
+    // Only the real data shows up in the patch
+    assert!(().diff(&()).unwrap().is_empty());
+    assert!(door.diff(&door.clone()).unwrap().is_empty());
+    let patch = door.diff(&renamed).unwrap();
+    assert_eq!(format!("{}", patch), "~ name: \"Back\"\n");
+
+    let mut applied = door.clone();
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied.name, "Back");
+  }
+);
+
+test!(
+  fn test_byte_size() {
+    let base = tools::Tester::random();
+    let mut updated = tools::Tester::random();
+    updated.string = "Quotes \" and \\ slashes\n and ünicode".to_string();
+
+    // The estimate can be larger, but must never be smaller than the real thing
+    for patch in &[base.diff(&base).unwrap(), base.diff(&updated).unwrap()] {
+      let json = patch.to_json().unwrap();
+      log::debug!(
+        "Estimated {} for {} bytes: {}",
+        patch.byte_size(),
+        json.len(),
+        json
+      );
+      assert!(patch.byte_size() >= json.len());
+    }
+  }
+);
+
 // Make sure we can apply a patch to a given struct
 test!(
   fn test_apply() {
@@ -259,9 +763,1501 @@ test!(
   }
 );
 
+test!(
+  fn test_patch_type() {
+    // Hand written impls get the full type name, and the derive just the struct name
+    let tester = tools::Tester::default();
+    assert!(tester.new_patch().patch_type().ends_with("::Tester"));
+    assert_eq!(tools::Derived::random().new_patch().patch_type(), "Derived");
+    assert_eq!(1u8.new_patch().patch_type(), "u8");
+  }
+);
+
+test!(
+  fn test_patch_eq() {
+    use std::collections::HashSet;
+    use std::rc::Rc;
+
+    let base = tools::Tester::default();
+    let mut updated = base.clone();
+    updated.integer = 1;
+
+    let mut expected = base.new_patch();
+    expected
+      .add(&"integer".to_string(), &serde_json::json!(1))
+      .unwrap();
+    assert_eq!(base.diff(&updated).unwrap(), expected);
+
+    // The validator doesn't take part in the comparison
+    let validated = expected.with_validator(Rc::new(protean::FnValidator::new(
+      |_key: &str, _value: &serde_json::Value| Ok(()),
+    )));
+    assert_eq!(validated, expected);
+
+    // So equal patches collapse in a set
+    let mut set = HashSet::new();
+    set.insert(expected);
+    set.insert(validated);
+    set.insert(base.diff(&base).unwrap());
+    assert_eq!(set.len(), 2);
+  }
+);
+
+test!(
+  fn test_three_way_merge() {
+    let base = tools::Tester::default();
+
+    let mut left = base.clone();
+    left.integer = 1;
+    left.float = 2.5;
+
+    let mut right = base.clone();
+    right.integer = 2;
+    right.string = "Right".to_string();
+
+    assert_eq!(
+      base
+        .diff(&left)
+        .unwrap()
+        .conflicts_with(&base.diff(&right).unwrap()),
+      vec!["integer".to_string()]
+    );
+
+    // Everything but the conflict is merged in and the conflict stays at the base value
+    let (merged, conflicts) = protean::Patch::three_way_merge(&base, &left, &right).unwrap();
+    log::debug!("Merged: {:#?}\nConflicts: {:#?}", merged, conflicts);
+    assert_eq!(conflicts, vec!["integer".to_string()]);
+    assert_eq!(merged.integer, 0);
+    assert_eq!(merged.float, 2.5);
+    assert_eq!(merged.string, "Right");
+  }
+);
+
+test!(
+  fn test_prefix_keys() {
+    let base = tools::Tester::default();
+    let mut updated = base.clone();
+    updated.nested = tools::Nested::random();
+
+    // Namespacing the nested diff is the same as diffing the parent
+    let nested = base.nested.diff(&updated.nested).unwrap();
+    let prefixed = nested.prefix_keys("nested");
+    assert_eq!(
+      format!("{}", prefixed),
+      format!("{}", base.diff(&updated).unwrap())
+    );
+
+    // And stripping the prefix gets us back to where we started
+    assert_eq!(
+      format!("{}", prefixed.strip_prefix("nested")),
+      format!("{}", nested)
+    );
+  }
+);
+
+test!(
+  fn test_derive() {
+    let base = tools::Derived::random();
+    let mut updated = base.clone();
+    updated.pk = uuid::Uuid::new_v4();
+    updated.name = "Frog".to_string();
+    updated.tester.integer = updated.tester.integer.wrapping_add(1);
+
+    // Skipped fields are ignored and renamed ones use the new key
+    let patch = base.diff(&updated).unwrap();
+    log::debug!("Derived Patch:\n{}", patch);
+    assert_eq!(
+      patch.child_keys(),
+      vec!["first_name".to_string(), "tester".to_string()]
+    );
+
+    let mut applied = base.clone();
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied.pk, base.pk);
+    assert_eq!(applied.name, "Frog");
+    assert_eq!(applied.tester.integer, updated.tester.integer);
+
+    // A skipped field can't be set through a patch
+    let pk = patch!(base, (("pk", uuid::Uuid::new_v4())));
+    assert!(applied.apply(&pk).is_err());
+
+    // Replacing the whole struct keeps the skipped field, and a change only to it isn't a difference
+    let shallow = protean::DiffOptions { max_depth: 0 };
+    let whole = base.diff_with_options(&updated, &shallow).unwrap();
+    let mut applied = base.clone();
+    applied.apply(&whole).unwrap();
+    assert_eq!(applied.pk, base.pk);
+    assert_eq!(applied.name, "Frog");
+    let mut changed = base.clone();
+    let keys: Vec<String> = patch.iter().map(|(key, _)| key.clone()).collect();
+    assert_eq!(changed.apply_changes(&whole).unwrap(), keys);
+    assert_eq!(changed.pk, base.pk);
+    let mut moved = base.clone();
+    moved.pk = uuid::Uuid::new_v4();
+    assert!(base.diff_with_options(&moved, &shallow).unwrap().is_empty());
+  }
+);
+
+test!(
+  fn test_diff_visit() {
+    let base = tools::Derived::random();
+    let mut updated = tools::Derived::random();
+    updated.tester.nested = tools::Nested::random();
+
+    // Visiting sees exactly what diff would have put in the patch
+    let mut visited = Vec::new();
+    base
+      .diff_visit(&updated, &mut |key, op| {
+        visited.push((key.to_string(), op.clone()));
+        Ok(())
+      })
+      .unwrap();
+
+    let patch = base.diff(&updated).unwrap();
+    assert_eq!(visited.len(), patch.iter().count());
+    for (key, op) in visited.iter() {
+      assert_eq!(patch.get_op(None, key), Some(op));
+    }
+  }
+);
+
+test!(
+  fn test_max_depth() {
+    use protean::DiffOptions;
+
+    let base = tools::Derived::random();
+    let mut updated = base.clone();
+    updated.tester.nested = tools::Nested::random();
+
+    // Unbounded is the same as a plain diff
+    let unbounded = base
+      .diff_with_options(&updated, &DiffOptions::default())
+      .unwrap();
+    assert_eq!(unbounded, base.diff(&updated).unwrap());
+
+    // Running out of depth replaces the rest of the subtree in a single change
+    let options = DiffOptions { max_depth: 1 };
+    let shallow = base.diff_with_options(&updated, &options).unwrap();
+    assert_eq!(shallow.child_keys(), vec!["tester"]);
+    assert_eq!(
+      shallow.get(None, "tester"),
+      Some(&serde_json::to_value(&updated.tester).unwrap())
+    );
+
+    let mut applied = base.clone();
+    applied.apply(&shallow).unwrap();
+    assert!(applied.diff(&updated).unwrap().is_empty());
+
+    // And nothing changed is still nothing, no matter the depth
+    let options = DiffOptions { max_depth: 0 };
+    assert!(base.diff_with_options(&base, &options).unwrap().is_empty());
+  }
+);
+
+test!(
+  fn test_registry() {
+    use protean::{Patch, PatchRegistry};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct User {
+      name: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Account {
+      balance: i64,
+    }
+
+    let mut registry = PatchRegistry::new();
+    registry
+      .register::<User>("User")
+      .register::<Account>("Account");
+
+    let mut user = User {
+      name: "Dave".to_string(),
+    };
+    let mut account = Account { balance: 0 };
+
+    // Patches come off the wire as JSON, with only the patch_type to say where they go
+    let inbox = vec![
+      User {
+        name: "Dave".to_string(),
+      }
+      .diff(&User {
+        name: "David".to_string(),
+      })
+      .unwrap()
+      .to_json()
+      .unwrap(),
+      account
+        .diff(&Account { balance: 100 })
+        .unwrap()
+        .to_json()
+        .unwrap(),
+    ];
+    for json in inbox {
+      let patch = Patch::from_json(&json).unwrap();
+      match patch.patch_type() {
+        "User" => registry.apply(&patch, &mut user).unwrap(),
+        _ => registry.apply(&patch, &mut account).unwrap(),
+      }
+    }
+    assert_eq!(user.name, "David");
+    assert_eq!(account.balance, 100);
+
+    // The handler checks it was given the right type
+    let patch = account.diff(&Account { balance: 5 }).unwrap();
+    assert!(registry.apply(&patch, &mut user).is_err());
+
+    // And types that were never registered are rejected
+    let patch = Patch::new("Unknown");
+    assert!(registry.apply(&patch, &mut user).is_err());
+  }
+);
+
+test!(
+  fn test_history_squash() {
+    use protean::{Historic, History};
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Counter {
+      count: i32,
+      tags: BTreeMap<String, i32>,
+      #[patch(skip)]
+      #[serde(skip)]
+      history: History,
+    }
+
+    impl<'a> Historic<'a> for Counter {
+      fn history(&self) -> &History {
+        &self.history
+      }
+      fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+      }
+    }
+
+    let mut counter = Counter {
+      count: 0,
+      tags: BTreeMap::new(),
+      history: History::new(),
+    };
+    let step = |counter: &Counter, update: &dyn Fn(&mut Counter)| {
+      let mut next = counter.clone();
+      update(&mut next);
+      counter.diff(&next).unwrap()
+    };
+
+    let patch = step(&counter, &|c| c.count = 1);
+    counter.record(&patch).unwrap();
+
+    // The tag is added and then removed again inside of the range being squashed
+    let patch = step(&counter, &|c| {
+      c.count = 2;
+      c.tags.insert("temp".to_string(), 1);
+    });
+    counter.record(&patch).unwrap();
+    let patch = step(&counter, &|c| {
+      c.count = 3;
+      c.tags.remove("temp");
+    });
+    counter.record(&patch).unwrap();
+    let patch = step(&counter, &|c| c.count = 4);
+    counter.record(&patch).unwrap();
+
+    let states: Vec<i32> = (0..=4).map(|i| counter.at(i).unwrap().count).collect();
+    assert_eq!(states, vec![0, 1, 2, 3, 4]);
+
+    // An empty range changes nothing
+    counter.squash(2, 2).unwrap();
+    assert_eq!(counter.history().len(), 4);
+
+    // States outside the range keep their values, while those after it shift down
+    counter.squash(1, 3).unwrap();
+    assert_eq!(counter.history().len(), 3);
+    let squashed = &counter.history().entries()[1].forward;
+    assert_eq!(format!("{}", squashed), "~ count: 3\n");
+    let states: Vec<i32> = (0..=3).map(|i| counter.at(i).unwrap().count).collect();
+    assert_eq!(states, vec![0, 1, 3, 4]);
+    assert!(counter.at(2).unwrap().tags.is_empty());
+
+    // Pop still walks back one entry at a time
+    counter.pop().unwrap();
+    counter.pop().unwrap();
+    assert_eq!(counter.count, 1);
+    assert!(counter.tags.is_empty());
+    assert!(counter.squash(0, 5).is_err());
+  }
+);
+
+test!(
+  fn test_history_squash_nested() {
+    use protean::{Historic, History};
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeSet;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Patchwork)]
+    struct Item {
+      name: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Basket {
+      items: Vec<Item>,
+      labels: BTreeSet<String>,
+      #[patch(skip)]
+      #[serde(skip)]
+      history: History,
+    }
+
+    impl<'a> Historic<'a> for Basket {
+      fn history(&self) -> &History {
+        &self.history
+      }
+      fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+      }
+    }
+
+    let mut basket = Basket {
+      items: Vec::new(),
+      labels: vec!["keep".to_string()].into_iter().collect(),
+      history: History::new(),
+    };
+    let mut next = basket.clone();
+    next.items.push(Item {
+      name: "a".to_string(),
+    });
+    next.labels.insert("x".to_string());
+    basket.record(&basket.diff(&next).unwrap()).unwrap();
+    next.items[0].name = "b".to_string();
+    next.labels.remove("x");
+    next.labels.remove("keep");
+    next.labels.insert("y".to_string());
+    basket.record(&basket.diff(&next).unwrap()).unwrap();
+    next.labels.insert("z".to_string());
+    basket.record(&basket.diff(&next).unwrap()).unwrap();
+
+    // The rename is folded into the added item, and the set lists are merged rather than replaced
+    basket.squash(0, 3).unwrap();
+    let squashed = basket.history().entries()[0].forward.clone();
+    assert_eq!(
+      format!("{}", squashed),
+      concat!(
+        "+ items.0: {\"name\":\"b\"}\n",
+        "+ labels.added: [\"y\",\"z\"]\n",
+        "+ labels.removed: [\"keep\"]\n"
+      )
+    );
+    let mut replayed = basket.at(0).unwrap();
+    assert!(replayed.items.is_empty());
+    replayed.apply(&squashed).unwrap();
+    assert_eq!(replayed.items, next.items);
+    assert_eq!(replayed.labels, next.labels);
+  }
+);
+
+test!(
+  fn test_checkpoints() {
+    use protean::{Historic, History, ProteanError};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Ledger {
+      balance: i64,
+      #[patch(skip)]
+      #[serde(skip)]
+      history: History,
+    }
+
+    impl<'a> Historic<'a> for Ledger {
+      fn history(&self) -> &History {
+        &self.history
+      }
+      fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+      }
+    }
+
+    let mut ledger = Ledger {
+      balance: 0,
+      history: History::new(),
+    };
+    let deposit = |ledger: &mut Ledger, amount: i64| {
+      let mut next = ledger.clone();
+      next.balance += amount;
+      let patch = ledger.diff(&next).unwrap();
+      ledger.record(&patch).unwrap();
+    };
+
+    deposit(&mut ledger, 10);
+    ledger.checkpoint("opened");
+    deposit(&mut ledger, 5);
+    ledger.checkpoint("bonus");
+    deposit(&mut ledger, 1);
+    deposit(&mut ledger, 2);
+
+    // The patches after the checkpoint are undone and dropped from the history
+    ledger.rollback_to("bonus").unwrap();
+    assert_eq!(ledger.balance, 15);
+    assert_eq!(ledger.history().len(), 2);
+
+    // Earlier checkpoints still work, but the ones after them are gone
+    deposit(&mut ledger, 100);
+    ledger.rollback_to("opened").unwrap();
+    assert_eq!(ledger.balance, 10);
+    assert_eq!(ledger.history().len(), 1);
+    for name in &["bonus", "never"] {
+      let err = ledger.rollback_to(name).unwrap_err();
+      assert!(matches!(
+        err.downcast_ref::<ProteanError>(),
+        Some(ProteanError::UnknownCheckpoint(_))
+      ));
+    }
+    assert_eq!(ledger.balance, 10);
+
+    // Popping past a checkpoint forgets it too
+    ledger.pop().unwrap();
+    assert!(ledger.rollback_to("opened").is_err());
+  }
+);
+
+test!(
+  fn test_changes_since() {
+    use protean::{Historic, History, Patch};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Settings {
+      name: String,
+      count: i32,
+      enabled: bool,
+      #[patch(skip)]
+      #[serde(skip)]
+      history: History,
+    }
+
+    impl<'a> Historic<'a> for Settings {
+      fn history(&self) -> &History {
+        &self.history
+      }
+      fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+      }
+    }
+
+    let mut settings = Settings {
+      name: "start".to_string(),
+      count: 0,
+      enabled: false,
+      history: History::new(),
+    };
+    let mut next = settings.clone();
+    next.name = "renamed".to_string();
+    next.count = 1;
+    settings.record(&settings.diff(&next).unwrap()).unwrap();
+    next.count = 2;
+    settings.record(&settings.diff(&next).unwrap()).unwrap();
+    next.enabled = true;
+    settings.record(&settings.diff(&next).unwrap()).unwrap();
+
+    // Each recorded patch is stamped with its place in the history
+    let changes = settings.changes_since(1).unwrap();
+    assert_eq!(format!("{}", changes), "~ count: 2\n~ enabled: true\n");
+    assert_eq!(changes.version("count"), Some(2));
+    assert_eq!(changes.version("enabled"), Some(3));
+    assert_eq!(changes.version("name"), None);
+    assert_eq!(settings.changes_since(3).unwrap().len(), 0);
+    assert_eq!(settings.changes_since(0).unwrap().len(), 3);
+
+    // Versions keep counting up after entries are popped or squashed away
+    settings.pop().unwrap();
+    next = settings.clone();
+    next.enabled = true;
+    settings.record(&settings.diff(&next).unwrap()).unwrap();
+    assert_eq!(settings.history().version(), 4);
+    assert_eq!(
+      settings.changes_since(3).unwrap().version("enabled"),
+      Some(4)
+    );
+    settings.squash(0, 3).unwrap();
+    next.count = 3;
+    settings.record(&settings.diff(&next).unwrap()).unwrap();
+    let latest = settings.changes_since(4).unwrap();
+    assert_eq!(format!("{}", latest), "~ count: 3\n");
+    assert_eq!(latest.version("count"), Some(5));
+
+    // Unstamped keys are always kept, and the versions survive a round trip through JSON
+    let mut patch = Patch::new("Settings");
+    patch
+      .add(&"name".to_string(), &serde_json::json!("other"))
+      .unwrap();
+    let mut merged = changes.compose(&patch).unwrap();
+    assert_eq!(merged.retain_changed_since(5).len(), 1);
+    merged.stamp(4);
+    let parsed = Patch::from_json(&merged.to_json().unwrap()).unwrap();
+    assert_eq!(parsed.version("name"), Some(4));
+    assert!(merged.to_json().unwrap().len() <= merged.byte_size());
+  }
+);
+
+test!(
+  fn test_apply_async() {
+    use futures::executor::block_on;
+    use protean::{AsyncPatchwork, AsyncValidator, BoxFuture};
+    use std::collections::BTreeSet;
+    use std::rc::Rc;
+
+    /// Stands in for a lookup against the table the key points at
+    struct ForeignKeys(BTreeSet<i64>);
+
+    impl AsyncValidator for ForeignKeys {
+      fn validate<'f>(
+        &'f self,
+        key: &'f str,
+        value: &'f serde_json::Value,
+      ) -> BoxFuture<'f, anyhow::Result<()>> {
+        Box::pin(async move {
+          let id = futures::future::ready(value.as_i64()).await;
+          match id.map_or(false, |id| self.0.contains(&id)) {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!("{} does not exist for '{}'", value, key)),
+          }
+        })
+      }
+    }
+
+    let known = Rc::new(ForeignKeys(vec![1, 2, 3].into_iter().collect()));
+    let mut ids = vec![1i64, 2];
+    let mut updated = ids.clone();
+    updated.push(3);
+    let patch = ids
+      .diff(&updated)
+      .unwrap()
+      .with_async_validator(known.clone());
+    block_on(ids.apply_async(&patch)).unwrap();
+    assert_eq!(ids, updated);
+
+    // A failed lookup leaves the target alone
+    updated.push(4);
+    let patch = ids.diff(&updated).unwrap().with_async_validator(known);
+    assert!(block_on(ids.apply_async(&patch)).is_err());
+    assert_eq!(ids, vec![1, 2, 3]);
+
+    // The synchronous apply doesn't run it
+    ids.apply(&patch).unwrap();
+    assert_eq!(ids, updated);
+  }
+);
+
+test!(
+  fn test_validator() {
+    use protean::{Patch, Validator};
+    use std::rc::Rc;
+
+    /// Only allows the keys it was configured with
+    struct KeyList(Vec<&'static str>);
+
+    impl Validator for KeyList {
+      fn validate(&self, key: &str, _value: &serde_json::Value) -> anyhow::Result<()> {
+        match self.0.contains(&key) {
+          true => Ok(()),
+          false => Err(anyhow::anyhow!("'{}' is not a legal key", key)),
+        }
+      }
+    }
+
+    let validator: Rc<dyn Validator> = Rc::new(KeyList(vec!["integer", "nested.level_2"]));
+    let tester = tools::Tester::default();
+    let mut patch = tester.new_patch().with_validator(validator.clone());
+    assert!(patch
+      .add(&"integer".to_string(), &serde_json::json!(1))
+      .is_ok());
+    assert!(patch
+      .add(&"string".to_string(), &serde_json::json!("no"))
+      .is_err());
+
+    // The same validator can be shared by any number of patches
+    let mut other = Patch::new("Tester").with_validator(validator);
+    assert!(other
+      .add(&"nested.level_2".to_string(), &serde_json::json!(2))
+      .is_ok());
+    assert!(other
+      .add(&"nested".to_string(), &serde_json::json!({}))
+      .is_err());
+  }
+);
+
+test!(
+  fn test_strict_keys() {
+    use protean::{Patch, ProteanError, Validator};
+    use serde::{Deserialize, Serialize};
+    use std::rc::Rc;
+
+    /// Knows the keys of an address, but accepts any value for them
+    struct AddressKeys;
+
+    impl Validator for AddressKeys {
+      fn validate(&self, _key: &str, _value: &serde_json::Value) -> anyhow::Result<()> {
+        Ok(())
+      }
+
+      fn is_valid_key(&self, key: &str) -> bool {
+        ["name", "address.city", "address.street"].contains(&key)
+      }
+    }
+
+    // Lenient patches keep the typo for apply to deal with
+    let lenient = Patch::new("Person").with_validator(Rc::new(AddressKeys));
+    assert!(!lenient.has_strict_keys());
+    let mut ingest = lenient.clone();
+    ingest
+      .add(&"adress.city".to_string(), &serde_json::json!("Boston"))
+      .unwrap();
+    assert_eq!(ingest.len(), 1);
+
+    // Strict ones refuse it straight away
+    let mut strict = lenient.with_strict_keys(true);
+    let err = strict
+      .add(&"adress.city".to_string(), &serde_json::json!("Boston"))
+      .unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<ProteanError>(),
+      Some(ProteanError::UnknownKey(key)) if key == "adress.city"
+    ));
+    assert!(strict.is_empty());
+    strict
+      .add(&"address.city".to_string(), &serde_json::json!("Boston"))
+      .unwrap();
+    assert!(strict.remove("address.zip").is_err());
+
+    // Turning it on afterwards catches what was already stored
+    assert!(ingest.validate().is_ok());
+    assert!(ingest.with_strict_keys(true).validate().is_err());
+
+    // Derived structs know their own keys, along with those of any derived struct inside of them
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Address {
+      city: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Person {
+      #[patch(rename = "full_name")]
+      name: String,
+      address: Address,
+      tags: Vec<String>,
+    }
+
+    let person = Person {
+      name: "Dave".to_string(),
+      address: Address {
+        city: "Boston".to_string(),
+      },
+      tags: Vec::new(),
+    };
+    let mut strict = person.new_patch().with_strict_keys(true);
+    for key in &["full_name", "address", "address.city", "tags.0"] {
+      strict
+        .add(&key.to_string(), &serde_json::json!("x"))
+        .unwrap();
+    }
+    for key in &["name", "adress.city", "address.zip"] {
+      assert!(strict
+        .add(&key.to_string(), &serde_json::json!("x"))
+        .is_err());
+    }
+  }
+);
+
+test!(
+  fn test_apply_changes() {
+    let mut derived = tools::Derived::random();
+
+    // Setting the age to what it already was isn't a change
+    let mut patch = derived.new_patch();
+    patch
+      .add(&"age".to_string(), &serde_json::json!(derived.age))
+      .unwrap();
+    patch
+      .add(&"first_name".to_string(), &serde_json::json!("Changed"))
+      .unwrap();
+    patch
+      .add(
+        &"tester.integer".to_string(),
+        &serde_json::json!(derived.tester.integer.wrapping_add(1)),
+      )
+      .unwrap();
+    let changed = derived.apply_changes(&patch).unwrap();
+    assert_eq!(changed, vec!["first_name", "tester.integer"]);
+    assert_eq!(derived.name, "Changed");
+
+    // Applying it again changes nothing
+    assert!(derived.apply_changes(&patch).unwrap().is_empty());
+
+    // Types without a derive compare their serialized values
+    let mut list = vec![1, 2, 3];
+    let patch = list.diff(&vec![1, 5, 3, 4]).unwrap();
+    assert_eq!(list.apply_changes(&patch).unwrap(), vec!["1", "3"]);
+  }
+);
+
+test!(
+  fn test_into_events() {
+    use protean::ChangeEvent;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    let mut before = tools::Tester::default();
+    before.integer = 1;
+    let mut after = before.clone();
+    after.integer = 2;
+    let events = before.diff(&after).unwrap().into_events(&before).unwrap();
+    assert_eq!(
+      events,
+      vec![ChangeEvent {
+        path: "integer".to_string(),
+        old: Some(json!(1)),
+        new: Some(json!(2)),
+      }]
+    );
+
+    // Additions have no old value and removals no new one
+    let before: BTreeMap<String, i32> = vec![("gone".to_string(), 1)].into_iter().collect();
+    let after: BTreeMap<String, i32> = vec![("new".to_string(), 2)].into_iter().collect();
+    let events = before.diff(&after).unwrap().into_events(&before).unwrap();
+    assert_eq!(
+      events,
+      vec![
+        ChangeEvent {
+          path: "gone".to_string(),
+          old: Some(json!(1)),
+          new: None,
+        },
+        ChangeEvent {
+          path: "new".to_string(),
+          old: None,
+          new: Some(json!(2)),
+        },
+      ]
+    );
+
+    // Renamed fields find their old value under the patch key rather than serde's name
+    let before = tools::Derived::random();
+    let mut after = before.clone();
+    after.name = "Frog".to_string();
+    let events = before.diff(&after).unwrap().into_events(&before).unwrap();
+    assert_eq!(
+      events,
+      vec![ChangeEvent {
+        path: "first_name".to_string(),
+        old: Some(json!("Dave")),
+        new: Some(json!("Frog")),
+      }]
+    );
+  }
+);
+
+test!(
+  fn test_diff_summary() {
+    use protean::ChangeCounts;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Account {
+      name: String,
+      profile: BTreeMap<String, String>,
+      orders: Vec<u32>,
+    }
+
+    let mut profile = BTreeMap::new();
+    profile.insert("city".to_string(), "Boston".to_string());
+    profile.insert("phone".to_string(), "555".to_string());
+    let before = Account {
+      name: "dave".to_string(),
+      profile,
+      orders: vec![1, 2],
+    };
+    let mut after = before.clone();
+    after
+      .profile
+      .insert("city".to_string(), "Denver".to_string());
+    after.profile.remove("phone");
+    after
+      .profile
+      .insert("email".to_string(), "d@example.com".to_string());
+    after.orders = vec![1, 5, 3];
+
+    let summary = before.diff(&after).unwrap().diff_summary();
+    assert_eq!(summary.len(), 2);
+    assert_eq!(
+      summary["profile"],
+      ChangeCounts {
+        added: 1,
+        modified: 1,
+        removed: 1,
+      }
+    );
+    assert_eq!(
+      summary["orders"],
+      ChangeCounts {
+        added: 1,
+        modified: 1,
+        removed: 0,
+      }
+    );
+    assert!(before.diff(&before).unwrap().diff_summary().is_empty());
+  }
+);
+
+test!(
+  fn test_apply_partial() {
+    let mut derived = tools::Derived::random();
+
+    // A newer version of the struct added a height
+    let mut patch = derived.new_patch();
+    patch
+      .add(&"age".to_string(), &serde_json::json!(40))
+      .unwrap();
+    patch
+      .add(&"height".to_string(), &serde_json::json!(180))
+      .unwrap();
+    assert!(derived.clone().apply(&patch).is_err());
+
+    let skipped = derived.apply_partial(&patch).unwrap();
+    assert_eq!(skipped, vec!["height"]);
+    assert_eq!(derived.age, 40);
+
+    // Values that don't fit are still an error, and stop the rest from being applied
+    patch
+      .add(&"age".to_string(), &serde_json::json!("forty one"))
+      .unwrap();
+    patch
+      .add(&"first_name".to_string(), &serde_json::json!("Changed"))
+      .unwrap();
+    assert!(derived.apply_partial(&patch).is_err());
+    assert_eq!(derived.age, 40);
+    assert_ne!(derived.name, "Changed");
+  }
+);
+
+test!(
+  fn test_apply_partial_nested() {
+    use protean::ProteanError;
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Clone, Default, Serialize, Deserialize, Patchwork)]
+    struct Order {
+      items: Vec<u32>,
+      note: Option<String>,
+      totals: BTreeMap<String, u32>,
+    }
+
+    // An Add can depend on the one before it, so a vec can grow by more than one
+    let mut order = Order {
+      items: vec![1],
+      ..Default::default()
+    };
+    let mut patch = order.new_patch();
+    patch.insert("items.1", &serde_json::json!(2)).unwrap();
+    patch.insert("items.2", &serde_json::json!(3)).unwrap();
+    patch.insert("discount", &serde_json::json!(5)).unwrap();
+    patch
+      .insert("discount.code", &serde_json::json!("SAVE"))
+      .unwrap();
+    let skipped = order.apply_partial(&patch).unwrap();
+    assert_eq!(skipped, vec!["discount", "discount.code"]);
+    assert_eq!(order.items, vec![1, 2, 3]);
+
+    // Keys the struct knows about that don't fit its current state are still errors
+    let missing = |key: &str| {
+      let mut patch = order.new_patch();
+      patch.add(&key.to_string(), &serde_json::json!(1)).unwrap();
+      let err = order.clone().apply_partial(&patch).unwrap_err();
+      matches!(
+        err.downcast_ref::<ProteanError>(),
+        Some(ProteanError::MissingValue(_))
+      )
+    };
+    assert!(missing("items.5"));
+    assert!(missing("totals.absent.inner"));
+    assert!(missing("note.len"));
+  }
+);
+
+test!(
+  fn test_normalize() {
+    let derived = tools::Derived::random();
+
+    let mut patch = derived.new_patch();
+    patch
+      .add(&"age".to_string(), &serde_json::json!(derived.age))
+      .unwrap();
+    patch
+      .add(
+        &"tester.string".to_string(),
+        &serde_json::json!(derived.tester.string),
+      )
+      .unwrap();
+    patch
+      .add(&"first_name".to_string(), &serde_json::json!("Changed"))
+      .unwrap();
+
+    // Only the entry that changes something is left
+    let normalized = patch.normalize(&derived).unwrap();
+    assert_eq!(format!("{}", normalized), "~ first_name: \"Changed\"\n");
+    assert_eq!(normalized.normalize(&derived).unwrap(), normalized);
+
+    // A patch that can't be applied at all is an error, not a no-op
+    let mut patch = derived.new_patch();
+    patch
+      .add(&"age".to_string(), &serde_json::json!("old"))
+      .unwrap();
+    assert!(patch.normalize(&derived).is_err());
+
+    // Adds that depend on each other are kept together
+    let base = vec![1u8];
+    let patch = base.diff(&vec![1, 2, 3]).unwrap();
+    let normalized = patch.normalize(&base).unwrap();
+    assert_eq!(normalized, patch);
+    let mut grown = base.clone();
+    grown.apply(&normalized).unwrap();
+    assert_eq!(grown, vec![1, 2, 3]);
+  }
+);
+
+test!(
+  fn test_validate_patch() {
+    let derived = tools::Derived::random();
+
+    let mut patch = derived.new_patch();
+    patch
+      .add(&"age".to_string(), &serde_json::json!(30))
+      .unwrap();
+    patch
+      .add(&"tester.string".to_string(), &serde_json::json!("Valid"))
+      .unwrap();
+    assert!(derived.validate_patch(&patch).is_ok());
+
+    // A value of the wrong type is caught before anything is applied
+    patch
+      .add(&"tester.integer".to_string(), &serde_json::json!("thirty"))
+      .unwrap();
+    assert!(derived.validate_patch(&patch).is_err());
+
+    // As are keys that don't exist on the struct
+    let mut patch = derived.new_patch();
+    patch
+      .add(&"height".to_string(), &serde_json::json!(180))
+      .unwrap();
+    assert!(derived.validate_patch(&patch).is_err());
+  }
+);
+
+test!(
+  fn test_try_apply_all() {
+    let mut tester = tools::Tester::default();
+
+    // Integer is applied before string, which can't be deserialized
+    let patch = patch!(tester, (("integer", 1)), (("string", 42)));
+    let result = patch.try_apply_all(&mut tester);
+    log::debug!("Result from the malformed patch: {:?}", result);
+    assert!(result.is_err());
+    assert_eq!(tester.integer, 0);
+    assert_eq!(tester.string, "");
+
+    // And a good patch still goes through
+    let patch = patch!(tester, (("integer", 1)), (("string", "42")));
+    patch.try_apply_all(&mut tester).unwrap();
+    assert_eq!(tester.integer, 1);
+    assert_eq!(tester.string, "42");
+  }
+);
+
+test!(
+  fn test_rebase() {
+    let v1 = tools::Tester::default();
+    let mut target = v1.clone();
+    target.integer = 1;
+    target.string = "Shared".to_string();
+    let patch = v1.diff(&target).unwrap();
+
+    // v2 already made the string change and touched an unrelated field
+    let mut v2 = v1.clone();
+    v2.string = "Shared".to_string();
+    v2.float = 1.5;
+    let rebased = patch.rebase(&v1, &v2).unwrap();
+    assert_eq!(format!("{}", rebased), "~ integer: 1\n");
+
+    // But changing the same value underneath the patch is a conflict
+    v2.integer = 5;
+    assert!(patch.rebase(&v1, &v2).is_err());
+  }
+);
+
 test!(
   fn test_vec() {
     // Vectors and arrays are going to have order changes and we want to make sure they are handled properly
+    let before = vec![tools::Tester::random(), tools::Tester::random()];
+    let mut after = before.clone();
+    after.insert(0, tools::Tester::random());
+    after[1].string = "Changed".to_string();
+
+    // Positionally, inserting at the front changes every index and adds one to the end
+    let positional = before.diff(&after).unwrap();
+    assert_eq!(positional.child_keys(), vec!["0", "1", "2"]);
+    assert!(matches!(
+      positional.get_op(None, "2"),
+      Some(PatchOp::Add(_))
+    ));
+
+    let mut applied = before.clone();
+    applied.apply(&positional).unwrap();
+    assert_eq!(applied.len(), 3);
+
+    // Shrinking removes the trailing elements
+    let shrunk = after.diff(&before).unwrap();
+    assert_eq!(shrunk.get_op(None, "2"), Some(&PatchOp::Remove));
+    applied.apply(&shrunk).unwrap();
+    assert_eq!(applied.len(), 2);
+
+    // Matching by pk only reports the insert and the real modification
+    let keyed = protean::diff_vec_by_key(&before, &after, |t| t.pk).unwrap();
+    log::debug!("Keyed Vec Patch:\n{}", keyed);
+    let (inserted, modified) = (after[0].pk.to_string(), after[1].pk.to_string());
+    let mut expected = vec![inserted.clone(), modified.clone()];
+    expected.sort();
+    assert_eq!(keyed.child_keys(), expected);
+    assert!(keyed.get(None, &inserted).is_some());
+    assert_eq!(
+      keyed.get(Some(modified), "string"),
+      Some(&serde_json::json!("Changed"))
+    );
+
+    // Removing an element marks its key
+    after.remove(2);
+    let removed = before[1].pk.to_string();
+    let keyed = protean::diff_vec_by_key(&before, &after, |t| t.pk).unwrap();
+    assert_eq!(keyed.get_op(None, &removed), Some(&PatchOp::Remove));
+    assert_eq!(keyed.get(None, &removed), None);
+
+    // Applying finds the elements by the same identity, with new ones going on the end
+    let mut applied = before.clone();
+    protean::apply_vec_by_key(&mut applied, &keyed, |t| t.pk).unwrap();
+    let pks = |items: &[tools::Tester]| items.iter().map(|t| t.pk).collect::<Vec<_>>();
+    assert_eq!(pks(&applied), vec![before[0].pk, after[0].pk]);
+    assert!(applied[0].diff(&after[1]).unwrap().is_empty());
+
+    // Identities become key segments, so they can't hold the separator
+    assert!(protean::diff_vec_by_key(&before, &after, |t| format!("{}.", t.pk)).is_err());
+  }
+);
+
+test!(
+  fn test_vec_deque() {
+    use std::collections::{LinkedList, VecDeque};
+
+    // A ring buffer makes room on the front and drops the oldest entry off the back
+    let before: VecDeque<u32> = vec![1, 2, 3].into_iter().collect();
+    let mut after = before.clone();
+    after.push_front(0);
+    let grown = before.diff(&after).unwrap();
+    assert_eq!(format!("{}", grown), "~ 0: 0\n~ 1: 1\n~ 2: 2\n+ 3: 3\n");
+
+    let mut applied = before.clone();
+    applied.apply(&grown).unwrap();
+    assert_eq!(applied, after);
+
+    after.pop_back();
+    let rotated = before.diff(&after).unwrap();
+    assert_eq!(rotated.len(), 3);
+    let mut applied = before.clone();
+    applied.apply(&rotated).unwrap();
+    assert_eq!(applied, after);
+
+    // Linked lists share the same keys
+    let before: LinkedList<u32> = vec![1, 2].into_iter().collect();
+    let mut after = before.clone();
+    after.push_back(3);
+    let patch = before.diff(&after).unwrap();
+    assert_eq!(
+      patch.get_op(None, "2"),
+      Some(&PatchOp::Add(serde_json::json!(3)))
+    );
+    let mut applied = before.clone();
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied, after);
+    assert!(applied.apply(&after.diff(&before).unwrap()).is_ok());
+    assert_eq!(applied, before);
+  }
+);
+
+test!(
+  fn test_array() {
+    let before: [f64; 3] = [1.0, 2.0, 3.0];
+    let after: [f64; 3] = [1.0, 2.5, 3.0];
+
+    // Only the component that changed is in the patch
+    let patch = before.diff(&after).unwrap();
+    assert_eq!(format!("{}", patch), "~ 1: 2.5\n");
+
+    let mut applied = before;
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied, after);
+  }
+);
+
+test!(
+  fn test_non_zero() {
+    use protean::{Patch, ProteanError};
+    use std::num::{NonZeroI64, NonZeroU32};
+
+    let mut id = NonZeroU32::new(1).unwrap();
+    let patch = id.diff(&NonZeroU32::new(7).unwrap()).unwrap();
+    assert_eq!(patch.get(None, "&self"), Some(&serde_json::json!(7)));
+    id.apply(&patch).unwrap();
+    assert_eq!(id.get(), 7);
+
+    // A zero from the wire is rejected instead of being stored
+    let mut zero = Patch::new("NonZeroU32");
+    zero
+      .add(&"&self".to_string(), &serde_json::json!(0))
+      .unwrap();
+    let err = id.apply(&zero).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<ProteanError>(),
+      Some(ProteanError::DeserializeFailed(_, _))
+    ));
+    assert_eq!(id.get(), 7);
+
+    let count = NonZeroI64::new(-3).unwrap();
+    assert!(count.diff(&count).unwrap().is_empty());
+  }
+);
+
+test!(
+  fn test_std_time() {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    let short = Duration::from_millis(1500);
+    assert!(short.diff(&Duration::from_millis(1500)).unwrap().is_empty());
+    let patch = short.diff(&Duration::new(2, 5)).unwrap();
+    assert_eq!(format!("{}", patch), "~ &self: {\"nanos\":5,\"secs\":2}\n");
+
+    let now = SystemTime::now();
+    let later = now + Duration::from_secs(60);
+    let patch = now.diff(&later).unwrap();
+    let mut applied = now;
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied, later);
+
+    // Serde can't write a time before the epoch, which comes back as an error
+    let before_epoch = UNIX_EPOCH - Duration::from_secs(1);
+    assert!(now.diff(&before_epoch).is_err());
+    assert!(before_epoch.diff(&before_epoch).unwrap().is_empty());
+  }
+);
+
+test!(
+  fn test_std_net_and_path() {
+    use std::net::{IpAddr, SocketAddr};
+    use std::path::PathBuf;
+
+    let ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let patch = ip.diff(&"::1".parse().unwrap()).unwrap();
+    assert_eq!(format!("{}", patch), "~ &self: \"::1\"\n");
+
+    let addr: SocketAddr = "10.0.0.1:80".parse().unwrap();
+    let moved: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let mut applied = addr;
+    applied.apply(&addr.diff(&moved).unwrap()).unwrap();
+    assert_eq!(applied, moved);
+
+    // Paths keep their exact string, even when the components are the same
+    let path = PathBuf::from("config/app.toml");
+    let doubled = PathBuf::from("config//app.toml");
+    assert_eq!(path, doubled);
+    let patch = path.diff(&doubled).unwrap();
+    assert!(!patch.is_empty());
+    let mut applied = path.clone();
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied.as_os_str(), doubled.as_os_str());
+    assert!(path.diff(&path.clone()).unwrap().is_empty());
+  }
+);
+
+test!(
+  fn test_uuid() {
+    let id = uuid::Uuid::new_v4();
+    assert!(id.diff(&id.clone()).unwrap().is_empty());
+
+    let other = uuid::Uuid::new_v4();
+    let patch = id.diff(&other).unwrap();
+    assert_eq!(patch.iter().count(), 1);
+    assert_eq!(patch.get(None, "&self"), Some(&serde_json::json!(other)));
+
+    let mut applied = id;
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied, other);
+  }
+);
+
+test!(
+  fn test_url() {
+    let home = url::Url::parse("https://example.com/").unwrap();
+    assert!(home.diff(&home.clone()).unwrap().is_empty());
+
+    let docs = url::Url::parse("https://example.com/docs?page=2").unwrap();
+    let patch = home.diff(&docs).unwrap();
+    assert_eq!(patch.iter().count(), 1);
+    assert_eq!(
+      patch.get(None, "&self"),
+      Some(&serde_json::json!("https://example.com/docs?page=2"))
+    );
+
+    let mut applied = home;
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied, docs);
+  }
+);
+
+test!(
+  fn test_chrono() {
+    use chrono::{NaiveDate, TimeZone, Utc};
+
+    let day = NaiveDate::from_ymd_opt(2020, 8, 1).unwrap();
+    let tomorrow = day.succ_opt().unwrap();
+    assert!(day.diff(&day.clone()).unwrap().is_empty());
+    let mut applied = day;
+    applied.apply(&day.diff(&tomorrow).unwrap()).unwrap();
+    assert_eq!(applied, tomorrow);
+
+    let moment = day.and_hms_opt(12, 0, 0).unwrap();
+    assert!(moment.diff(&moment.clone()).unwrap().is_empty());
+    let next = tomorrow.and_hms_opt(8, 15, 30).unwrap();
+    let patch = moment.diff(&next).unwrap();
+    assert_eq!(format!("{}", patch), "~ &self: \"2020-08-02T08:15:30\"\n");
+    let mut applied = moment;
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied, next);
+
+    let start = Utc.from_utc_datetime(&moment);
+    assert!(start.diff(&start.clone()).unwrap().is_empty());
+    let later = Utc.from_utc_datetime(&next);
+    let patch = start.diff(&later).unwrap();
+    assert_eq!(patch.iter().count(), 1);
+    assert_eq!(patch.get(None, "&self"), Some(&serde_json::json!(later)));
+    let mut applied = start;
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied, later);
+  }
+);
+
+test!(
+  fn test_result() {
+    let ok: Result<tools::Nested, String> = Ok(tools::Nested::random());
+    let err: Result<tools::Nested, String> = Err("Timed out".to_string());
+
+    // Changing variants replaces the whole result
+    let patch = ok.diff(&err).unwrap();
+    assert_eq!(format!("{}", patch), "~ &self: {\"Err\":\"Timed out\"}\n");
+    let mut applied = ok.clone();
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied.unwrap_err(), "Timed out");
+
+    // While the same variant diffs what it holds
+    let other: Result<tools::Nested, String> = Err("Refused".to_string());
+    let patch = err.diff(&other).unwrap();
+    assert_eq!(format!("{}", patch), "~ Err: \"Refused\"\n");
+    let mut applied = err.clone();
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied.unwrap_err(), "Refused");
+
+    // Patching inside of the wrong variant fails
+    assert!(ok.clone().apply(&patch).is_err());
+  }
+);
+
+test!(
+  fn test_shared_state() {
+    use protean::ProteanError;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    // Plain Arcs copy on write, so other holders keep the old value
+    let before = Arc::new(vec![1u8, 2]);
+    let shared = before.clone();
+    let mut patched = before.clone();
+    let patch = before.diff(&Arc::new(vec![1, 3])).unwrap();
+    patched.apply(&patch).unwrap();
+    assert_eq!(*patched, vec![1, 3]);
+    assert_eq!(*shared, vec![1, 2]);
+
+    // A Mutex is changed in place, where everyone holding it sees the change
+    let mut state: Arc<Mutex<BTreeMap<String, i32>>> = Arc::new(Mutex::new(BTreeMap::new()));
+    let snapshot = Arc::new(Mutex::new(state.lock().unwrap().clone()));
+    snapshot.lock().unwrap().insert("hits".to_string(), 1);
+    let patch = state.diff(&snapshot).unwrap();
+    assert_eq!(patch.child_keys(), vec!["hits"]);
+    let holder = state.clone();
+    state.apply(&patch).unwrap();
+    assert_eq!(holder.lock().unwrap().get("hits"), Some(&1));
+    assert!(state.diff(&holder).unwrap().is_empty());
+
+    // A panic while holding the lock is reported rather than passed along
+    let poisoner = holder.clone();
+    let _ = std::thread::spawn(move || {
+      let _guard = poisoner.lock().unwrap();
+      panic!("Poisoning the lock on purpose");
+    })
+    .join();
+    let err = state.apply(&patch).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<ProteanError>(),
+      Some(ProteanError::PoisonedLock(_))
+    ));
+    assert!(snapshot.diff(&state).is_err());
+  }
+);
+
+test!(
+  fn test_shared_state_snapshots() {
+    use protean::{Historic, History};
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+    struct Inventory {
+      name: String,
+      counts: Arc<Mutex<BTreeMap<String, i32>>>,
+      limit: u32,
+      #[patch(skip)]
+      #[serde(skip)]
+      history: History,
+    }
+
+    impl<'a> Historic<'a> for Inventory {
+      fn history(&self) -> &History {
+        &self.history
+      }
+      fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+      }
+    }
+
+    let mut inventory = Inventory {
+      name: "Shelf".to_string(),
+      counts: Arc::new(Mutex::new(BTreeMap::new())),
+      limit: 10,
+      history: History::new(),
+    };
+    let holder = inventory.counts.clone();
+    let next = inventory.snapshot().unwrap();
+    next.counts.lock().unwrap().insert("apples".to_string(), 3);
+    assert!(inventory.counts.lock().unwrap().is_empty());
+
+    // Recording sees the state before the lock was changed, so the undo isn't empty
+    inventory.record(&inventory.diff(&next).unwrap()).unwrap();
+    assert_eq!(holder.lock().unwrap().get("apples"), Some(&3));
+    assert!(!inventory.history().entries()[0].undo.is_empty());
+    inventory.pop().unwrap();
+    assert!(holder.lock().unwrap().is_empty());
+
+    // A failed patch puts the guarded value back for everyone holding it
+    let mut patch = inventory.diff(&next).unwrap();
+    patch
+      .add(&"limit".to_string(), &serde_json::json!("many"))
+      .unwrap();
+    assert!(patch.try_apply_all(&mut inventory).is_err());
+    assert!(holder.lock().unwrap().is_empty());
+    assert!(Arc::ptr_eq(&holder, &inventory.counts));
+    assert_eq!(inventory.limit, 10);
+  }
+);
+
+test!(
+  fn test_reference() {
+    let base = tools::Derived::random();
+    let mut updated = base.clone();
+    updated.age = updated.age.wrapping_add(1);
+
+    // A reference diffs the same as the value it points at
+    let (left, right) = (&base, &updated);
+    assert_eq!(
+      Patchwork::diff(&left, &right).unwrap(),
+      base.diff(&updated).unwrap()
+    );
+    assert_eq!(Patchwork::new_patch(&left).patch_type(), "Derived");
+
+    // But it can't be changed through
+    let mut reference = &base;
+    assert!(Patchwork::apply(&mut reference, &base.diff(&base).unwrap()).is_ok());
+    assert!(Patchwork::apply(&mut reference, &base.diff(&updated).unwrap()).is_err());
+  }
+);
+
+test!(
+  fn test_cow() {
+    use std::borrow::Cow;
+
+    // Only the data matters, not whether it is borrowed
+    let borrowed: Cow<str> = Cow::Borrowed("Protean");
+    let owned: Cow<str> = Cow::Owned("Protean".to_string());
+    assert!(borrowed.diff(&owned).unwrap().is_empty());
+
+    let changed: Cow<str> = Cow::Owned("Patchwork".to_string());
+    let patch = borrowed.diff(&changed).unwrap();
+    assert_eq!(format!("{}", patch), "~ &self: \"Patchwork\"\n");
+
+    let mut applied = borrowed.clone();
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied, changed);
+  }
+);
+
+test!(
+  fn test_btree() {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    let before: BTreeMap<String, i32> = vec![("a".to_string(), 1), ("b".to_string(), 2)]
+      .into_iter()
+      .collect();
+    let mut after = before.clone();
+    after.insert("b".to_string(), 3);
+    after.insert("c".to_string(), 4);
+    after.remove("a");
+
+    let patch = before.diff(&after).unwrap();
+    log::debug!("BTreeMap Patch:\n{}", patch);
+    assert_eq!(format!("{}", patch), "- a\n~ b: 3\n+ c: 4\n");
+
+    let mut applied = before.clone();
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied, after);
+
+    // Keys are used as key segments, so one with a '.' would be split on apply
+    let mut dotted = before.clone();
+    dotted.insert("d.e".to_string(), 5);
+    let err = before.diff(&dotted).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<protean::ProteanError>(),
+      Some(protean::ProteanError::MalformedPatch(_))
+    ));
+
+    let before: BTreeSet<u8> = vec![1, 2, 3].into_iter().collect();
+    let after: BTreeSet<u8> = vec![2, 3, 4, 5].into_iter().collect();
+    let patch = before.diff(&after).unwrap();
+    assert_eq!(format!("{}", patch), "+ added: [4,5]\n+ removed: [1]\n");
+
+    let mut applied = before.clone();
+    applied.apply(&patch).unwrap();
+    assert_eq!(applied, after);
   }
 );
 
@@ -0,0 +1,15 @@
+//! Check the errors given when the derive is misused
+//!
+//! The expected compiler output lives next to each case in tests/ui. After an intentional change to a
+//! message, regenerate them with `TRYBUILD=overwrite cargo test --test test_compile_fail`.
+//!
+//! The wording of the errors changes between compiler releases, so the expected output is pinned to the
+//! toolchain it was generated with and the test is ignored on any other. Bump the version here when
+//! regenerating.
+
+#[test]
+#[rustversion::attr(not(stable(1.95)), ignore)]
+fn test_compile_fail() {
+  let cases = trybuild::TestCases::new();
+  cases.compile_fail("tests/ui/*.rs");
+}
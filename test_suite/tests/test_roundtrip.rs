@@ -0,0 +1,72 @@
+//! Property tests for the contract everything else relies on
+//!
+//! For any two values a and b:
+//! - a patched with a.diff(&b) is b
+//! - b patched with a.diff(&b).reverse(&a) is a
+
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+
+use protean::Patchwork;
+
+fn round_trip<'a, T>(a: T, b: T) -> Result<(), TestCaseError>
+where
+  T: Patchwork<'a> + PartialEq,
+{
+  let patch = a.diff(&b).unwrap();
+  let mut forward = a.clone();
+  forward.apply(&patch).unwrap();
+  prop_assert_eq!(&forward, &b, "Applying {} to {:?}", patch, a);
+
+  let reverse = patch.reverse(&a).unwrap();
+  let mut backward = b.clone();
+  backward.apply(&reverse).unwrap();
+  prop_assert_eq!(&backward, &a, "Applying {} to {:?}", reverse, b);
+  Ok(())
+}
+
+proptest! {
+  #[test]
+  fn test_integer_round_trip(a: i64, b: i64) {
+    round_trip(a, b)?;
+  }
+
+  #[test]
+  fn test_float_round_trip(a in -1e9f64..1e9, b in -1e9f64..1e9) {
+    round_trip(a, b)?;
+  }
+
+  #[test]
+  fn test_string_round_trip(a: String, b: String) {
+    round_trip(a, b)?;
+  }
+
+  #[test]
+  fn test_option_round_trip(a: Option<u16>, b: Option<u16>) {
+    round_trip(a, b)?;
+  }
+
+  #[test]
+  fn test_vec_round_trip(
+    a in prop::collection::vec(any::<Option<String>>(), 0..8),
+    b in prop::collection::vec(any::<Option<String>>(), 0..8),
+  ) {
+    round_trip(a, b)?;
+  }
+
+  #[test]
+  fn test_nested_vec_round_trip(
+    a in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..4), 0..4),
+    b in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..4), 0..4),
+  ) {
+    round_trip(a, b)?;
+  }
+
+  #[test]
+  fn test_map_round_trip(
+    a in prop::collection::btree_map("[a-z]{1,4}", any::<i32>(), 0..6),
+    b in prop::collection::btree_map("[a-z]{1,4}", any::<i32>(), 0..6),
+  ) {
+    round_trip::<BTreeMap<String, i32>>(a, b)?;
+  }
+}
@@ -0,0 +1,10 @@
+use protean::Patchwork;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+struct Renamed {
+  #[patch(rename = 5)]
+  value: i32,
+}
+
+fn main() {}
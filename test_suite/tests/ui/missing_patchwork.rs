@@ -0,0 +1,11 @@
+use protean::Patchwork;
+use serde::{Deserialize, Serialize};
+
+// Range is Serialize, Deserialize, Clone and Debug, but nothing like it implements Patchwork
+#[derive(Debug, Clone, Serialize, Deserialize, Patchwork)]
+struct Outer {
+  name: String,
+  window: std::ops::Range<u32>,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use protean::Patchwork;
+
+#[derive(Patchwork)]
+union Bits {
+  int: u32,
+  float: f32,
+}
+
+fn main() {}